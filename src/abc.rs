@@ -0,0 +1,264 @@
+use crate::notation::{
+    key_tonic, spell, tuplet_runs, Bar, Cell, ChordSymbol, NotatedPart, NoteValue, Score,
+    BARS_PER_LINE,
+};
+use std::collections::HashMap;
+
+/// Order in which key signatures add sharps. Flats are added in reverse.
+const SHARPS: [char; 7] = ['F', 'C', 'G', 'D', 'A', 'E', 'B'];
+
+/// Writes the melodic parts of `composition` as an ABC tune, a voice per part, with the chord
+/// progression as chord symbols on the first voice.
+pub fn export(composition: &redact_composer::Composition) -> String {
+    let score = Score::of(composition);
+    let first = score.bars.first();
+
+    let mut abc = String::from("X:1\nT:Composition\n");
+    abc.push_str(&format!(
+        "M:{}/{}\nL:1/16\nQ:{}\nK:{}\n",
+        first.map(|bar| bar.beats_per_bar).unwrap_or(4),
+        first.map(|bar| bar.beat_type).unwrap_or(4),
+        first.map(tempo).unwrap_or_else(|| String::from("1/4=120")),
+        key(first.map(|bar| bar.fifths).unwrap_or(0))
+    ));
+
+    let melodic = score.parts.iter().filter(|part| !part.is_percussion());
+    for (idx, part) in melodic.enumerate() {
+        // Markings shared by all parts are only written once, on the first voice
+        abc.push_str(&voice(&score, part, idx + 1, idx == 0));
+    }
+
+    abc
+}
+
+/// The tempo of `bar` in its own beat unit.
+fn tempo(bar: &Bar) -> String {
+    format!("1/{}={}", bar.beat_type, bar.beat_bpm().round())
+}
+
+fn voice(score: &Score, part: &NotatedPart, number: usize, marked: bool) -> String {
+    let mut bars = vec![];
+    let mut previous: Option<&Bar> = None;
+    for (bar, cells) in score.bars.iter().zip(score.cells(&part.events)) {
+        let mut abc = String::new();
+        // The header covers the first bar
+        if previous.is_some() {
+            let changes = bar.changes(previous);
+            if changes.time {
+                abc.push_str(&format!("[M:{}/{}]", bar.beats_per_bar, bar.beat_type));
+            }
+            if changes.key {
+                abc.push_str(&format!("[K:{}]", key(bar.fifths)));
+            }
+            if marked && changes.tempo {
+                abc.push_str(&format!("[Q:{}]", tempo(bar)));
+            }
+        }
+        if marked {
+            if let Some(mark) = &bar.rehearsal {
+                abc.push_str(&format!("\"^{}\"", mark));
+            }
+        }
+
+        let chords = if marked {
+            score.chords_during(bar.timing.clone()).collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        if chords.is_empty() && cells.iter().all(|cell| cell.pitches.is_empty()) {
+            abc.push_str("Z ");
+        } else {
+            // Accidentals last until the end of the bar
+            let mut accidentals = HashMap::new();
+            for run in tuplet_runs(&cells) {
+                if run[0].value.triplet {
+                    abc.push_str(&format!("(3:2:{}", run.len()));
+                }
+                for cell in run {
+                    let timing = cell.start..(cell.start + cell.value.length());
+                    for (_, symbol) in chords.iter().filter(|(start, _)| timing.contains(start)) {
+                        abc.push_str(&format!("\"{}\"", chord_name(symbol, bar.fifths)));
+                    }
+                    abc.push_str(&note(cell, bar.fifths, &mut accidentals));
+                }
+                abc.push(' ');
+            }
+        }
+
+        abc.push('|');
+        bars.push(abc);
+        previous = Some(bar);
+    }
+
+    let lines = bars
+        .chunks(BARS_PER_LINE)
+        .map(|line| format!("{}\n", line.join(" ")))
+        .collect::<String>();
+
+    format!(
+        "V:{} name=\"{}\" clef={}\n{}",
+        number,
+        part.name(),
+        part.clef().name(),
+        lines
+    )
+}
+
+/// A note, chord or rest, tied to the next if needed.
+fn note(cell: &Cell, fifths: i32, accidentals: &mut HashMap<(char, i32), i32>) -> String {
+    let length = length(cell.value);
+    let tie = if cell.tied_to_next { "-" } else { "" };
+    if cell.pitches.is_empty() {
+        return format!("z{}", length);
+    }
+
+    let pitches = cell
+        .pitches
+        .iter()
+        .map(|pitch| {
+            let (step, alter) = spell(*pitch, fifths);
+            let octave = *pitch as i32 / 12 - 1;
+
+            let current = accidentals
+                .get(&(step, octave))
+                .copied()
+                .unwrap_or_else(|| key_alter(step, fifths));
+            let accidental = if alter == current {
+                ""
+            } else {
+                accidentals.insert((step, octave), alter);
+                match alter {
+                    1 => "^",
+                    -1 => "_",
+                    _ => "=",
+                }
+            };
+
+            // Middle C is `C`, with the octave above in lowercase
+            let name = if octave >= 5 {
+                format!(
+                    "{}{}",
+                    step.to_ascii_lowercase(),
+                    "'".repeat((octave - 5) as usize)
+                )
+            } else {
+                format!("{}{}", step, ",".repeat((4 - octave) as usize))
+            };
+
+            format!("{}{}", accidental, name)
+        })
+        .collect::<String>();
+
+    if cell.pitches.len() > 1 {
+        format!("[{}]{}{}", pitches, length, tie)
+    } else {
+        format!("{}{}{}", pitches, length, tie)
+    }
+}
+
+/// The written length in sixteenths, before any tuplet scaling.
+fn length(value: NoteValue) -> String {
+    let sixteenths = 16 / value.denominator;
+
+    match (sixteenths, value.dotted) {
+        (0, _) => String::from("/"),
+        (1, false) => String::new(),
+        (_, true) => (sixteenths * 3 / 2).to_string(),
+        (_, false) => sixteenths.to_string(),
+    }
+}
+
+/// The alteration of `step` in the key signature.
+fn key_alter(step: char, fifths: i32) -> i32 {
+    let sharps = &SHARPS[..fifths.clamp(0, 7) as usize];
+    let flats = &SHARPS[SHARPS.len() - (-fifths).clamp(0, 7) as usize..];
+
+    if sharps.contains(&step) {
+        1
+    } else if flats.contains(&step) {
+        -1
+    } else {
+        0
+    }
+}
+
+fn key(fifths: i32) -> String {
+    let (step, alter) = key_tonic(fifths);
+
+    format!("{}{}", step, accidental(alter))
+}
+
+fn chord_name(symbol: &ChordSymbol, fifths: i32) -> String {
+    let (step, alter) = spell(symbol.root, fifths);
+
+    format!("{}{}{}", step, accidental(alter), symbol.kind.suffix())
+}
+
+fn accidental(alter: i32) -> &'static str {
+    match alter {
+        1 => "#",
+        -1 => "b",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::fixtures::*;
+
+    #[test]
+    fn lengths_are_in_sixteenths() {
+        assert_eq!(length(NoteValue::new(16, false, false)), "");
+        assert_eq!(length(QUARTER), "4");
+        assert_eq!(length(NoteValue::new(8, true, false)), "3");
+        assert_eq!(length(NoteValue::new(1, false, false)), "16");
+        // Triplets are written at their unscaled length
+        assert_eq!(length(EIGHTH_TRIPLET), "2");
+        assert_eq!(length(NoteValue::new(32, false, false)), "/");
+    }
+
+    #[test]
+    fn octaves_are_marked_around_middle_c() {
+        let mut accidentals = HashMap::new();
+
+        for (pitch, name) in [
+            (60, "C4"),
+            (72, "c4"),
+            (84, "c'4"),
+            (48, "C,4"),
+            (36, "C,,4"),
+        ] {
+            assert_eq!(note(&cell(0, QUARTER, &[pitch]), 0, &mut accidentals), name);
+        }
+    }
+
+    #[test]
+    fn accidentals_follow_key_and_last_until_bar_end() {
+        let mut accidentals = HashMap::new();
+
+        assert_eq!(note(&cell(0, EIGHTH, &[66]), 0, &mut accidentals), "^F2");
+        assert_eq!(note(&cell(0, EIGHTH, &[66]), 0, &mut accidentals), "F2");
+        assert_eq!(note(&cell(0, EIGHTH, &[65]), 0, &mut accidentals), "=F2");
+        // A different octave isn't affected
+        assert_eq!(note(&cell(0, EIGHTH, &[77]), 0, &mut accidentals), "f2");
+
+        let mut accidentals = HashMap::new();
+        assert_eq!(note(&cell(0, EIGHTH, &[66]), 1, &mut accidentals), "F2");
+        assert_eq!(note(&cell(0, EIGHTH, &[65]), 1, &mut accidentals), "=F2");
+        assert_eq!(note(&cell(0, EIGHTH, &[70]), -1, &mut accidentals), "B2");
+    }
+
+    #[test]
+    fn chords_rests_and_ties() {
+        let half = NoteValue::new(2, false, false);
+        let tied = Cell {
+            tied_to_next: true,
+            ..cell(0, half, &[60, 64, 67])
+        };
+        let mut accidentals = HashMap::new();
+
+        assert_eq!(note(&tied, 0, &mut accidentals), "[CEG]8-");
+        assert_eq!(note(&cell(0, half, &[]), 0, &mut accidentals), "z8");
+    }
+}
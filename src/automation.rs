@@ -0,0 +1,235 @@
+use crate::util::Modulation;
+use midly::num::u7;
+use midly::{MidiMessage, PitchBend};
+use redact_composer::Element;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+const EXPRESSION: u8 = 11;
+const BRIGHTNESS: u8 = 74;
+/// The pitch bend range (in semitones) assumed of synthesizers, as per General MIDI.
+const BEND_RANGE: f32 = 2.0;
+
+/// The parameter an [`Automation`] varies.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum AutomationLane {
+    /// CC11, ranging `0..=127`.
+    Expression,
+    /// CC74 filter cutoff, ranging `0..=127`.
+    Brightness,
+    /// Pitch bend in semitones.
+    PitchBend,
+}
+
+impl AutomationLane {
+    /// The General MIDI default value, which parts start from.
+    pub fn base(&self) -> f32 {
+        match self {
+            AutomationLane::Expression => 127.0,
+            AutomationLane::Brightness => 64.0,
+            AutomationLane::PitchBend => 0.0,
+        }
+    }
+}
+
+/// The curve an [`Automation`] follows across its timing.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum AutomationShape {
+    /// Rises steadily from `from` to `to`.
+    Ramp,
+    /// Rises from `from` to `to` at the middle, then falls back.
+    Swell,
+}
+
+/// A time-varying control of the [`Part`](redact_composer::elements::Part) it is placed in, from
+/// `from` to `to` following `shape`.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Automation {
+    pub lane: AutomationLane,
+    pub shape: AutomationShape,
+    pub from: f32,
+    pub to: f32,
+}
+
+impl Automation {
+    /// Holds `lane` at its [`base`](AutomationLane::base), undoing earlier automation.
+    pub fn reset(lane: AutomationLane) -> Automation {
+        Automation {
+            lane,
+            shape: AutomationShape::Ramp,
+            from: lane.base(),
+            to: lane.base(),
+        }
+    }
+
+    /// Samples the curve across `timing` every `resolution` ticks, ending on its final value.
+    pub fn messages(&self, timing: Range<i32>, resolution: i32) -> Vec<(i32, MidiMessage)> {
+        let len = (timing.end - timing.start).max(1) as f32;
+        // Offset by half a period so curves start from 0.0 rather than the middle
+        let curve = match self.shape {
+            AutomationShape::Ramp => Modulation::sawtooth(len, len / 2.0),
+            AutomationShape::Swell => Modulation::triangle(len, len / 2.0),
+        };
+        let final_value = match self.shape {
+            AutomationShape::Ramp => 1.0,
+            AutomationShape::Swell => 0.0,
+        };
+
+        let mut messages: Vec<(i32, MidiMessage)> = vec![];
+        let samples = (timing.start..timing.end)
+            .step_by(resolution.max(1) as usize)
+            .map(|tick| (tick, curve.value((tick - timing.start) as f32)))
+            .chain([(timing.end, final_value)]);
+        for (tick, position) in samples {
+            let message = self.message(self.from + (self.to - self.from) * position);
+            if messages
+                .last()
+                .map(|(_, last)| *last != message)
+                .unwrap_or(true)
+            {
+                messages.push((tick, message));
+            }
+        }
+
+        messages
+    }
+
+    fn message(&self, value: f32) -> MidiMessage {
+        let controller = |controller: u8| MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value.round().clamp(0.0, 127.0) as u8),
+        };
+
+        match self.lane {
+            AutomationLane::Expression => controller(EXPRESSION),
+            AutomationLane::Brightness => controller(BRIGHTNESS),
+            AutomationLane::PitchBend => MidiMessage::PitchBend {
+                bend: PitchBend::from_f32((value / BEND_RANGE).clamp(-1.0, 1.0)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expression(value: u8) -> MidiMessage {
+        MidiMessage::Controller {
+            controller: u7::new(EXPRESSION),
+            value: u7::new(value),
+        }
+    }
+
+    #[test]
+    fn ramp_rises_to_its_final_value() {
+        let ramp = Automation {
+            lane: AutomationLane::Expression,
+            shape: AutomationShape::Ramp,
+            from: 0.0,
+            to: 100.0,
+        };
+
+        assert_eq!(
+            ramp.messages(0..100, 25),
+            vec![
+                (0, expression(0)),
+                (25, expression(25)),
+                (50, expression(50)),
+                (75, expression(75)),
+                (100, expression(100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn swell_peaks_in_the_middle_and_falls_back() {
+        let swell = Automation {
+            lane: AutomationLane::Expression,
+            shape: AutomationShape::Swell,
+            from: 90.0,
+            to: 110.0,
+        };
+
+        assert_eq!(
+            swell.messages(100..200, 25),
+            vec![
+                (100, expression(90)),
+                (125, expression(100)),
+                (150, expression(110)),
+                (175, expression(100)),
+                (200, expression(90)),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_values_are_skipped() {
+        let reset = Automation::reset(AutomationLane::Expression);
+
+        assert_eq!(reset.messages(0..100, 10), vec![(0, expression(127))]);
+    }
+
+    #[test]
+    fn pitch_bend_is_scaled_to_bend_range() {
+        let slide = Automation {
+            lane: AutomationLane::PitchBend,
+            shape: AutomationShape::Ramp,
+            from: -BEND_RANGE,
+            to: 0.0,
+        };
+        let messages = slide.messages(0..10, 10);
+
+        assert_eq!(
+            messages.first(),
+            Some(&(
+                0,
+                MidiMessage::PitchBend {
+                    bend: PitchBend::from_f32(-1.0)
+                }
+            ))
+        );
+        assert_eq!(
+            messages.last(),
+            Some(&(
+                10,
+                MidiMessage::PitchBend {
+                    bend: PitchBend::from_f32(0.0)
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn values_are_clamped_to_controller_range() {
+        let ramp = Automation {
+            lane: AutomationLane::Brightness,
+            shape: AutomationShape::Ramp,
+            from: -20.0,
+            to: 200.0,
+        };
+        let values = ramp
+            .messages(0..100, 50)
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            values,
+            vec![
+                MidiMessage::Controller {
+                    controller: u7::new(BRIGHTNESS),
+                    value: u7::new(0),
+                },
+                MidiMessage::Controller {
+                    controller: u7::new(BRIGHTNESS),
+                    value: u7::new(90),
+                },
+                MidiMessage::Controller {
+                    controller: u7::new(BRIGHTNESS),
+                    value: u7::new(127),
+                },
+            ]
+        );
+    }
+}
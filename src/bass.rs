@@ -0,0 +1,361 @@
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use redact_composer::musical::elements::{Chord, Key};
+use redact_composer::musical::{Interval, Note, NoteIterator};
+use std::ops::{Range, RangeInclusive};
+
+/// Chord-relative degrees a [`Riff`] is built from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum RiffDegree {
+    Root,
+    Fifth,
+    Octave,
+    /// Falls back to [`RiffDegree::Fifth`] where the key does not contain the chord's flat seventh.
+    FlatSeventh,
+}
+
+/// A short rhythmic pattern of chord degrees, repeated and transposed to follow each chord.
+#[derive(Debug, Clone)]
+pub struct Riff {
+    /// Degrees with their timings, relative to the start of the riff.
+    notes: Vec<(RiffDegree, Range<i32>)>,
+    len: i32,
+}
+
+impl Riff {
+    /// Creates a riff spanning `bars` bars.
+    pub fn random(
+        bars: i32,
+        beats_per_bar: i32,
+        beat: i32,
+        accents: &[i32],
+        rng: &mut impl Rng,
+    ) -> Riff {
+        let bar = beats_per_bar * beat;
+        let len = bar * bars;
+        let groups = if beats_per_bar % 2 == 0 || beats_per_bar < 3 {
+            vec![2.min(beats_per_bar); (beats_per_bar / 2).max(1) as usize]
+        } else {
+            let mut groups = vec![2; ((beats_per_bar - 3) / 2) as usize];
+            groups.push(3);
+            groups
+        };
+
+        let mut attacks = (0..bars)
+            .flat_map(|b| {
+                groups
+                    .iter()
+                    .scan(b * bar, |group_start, group_len| {
+                        let start = *group_start;
+                        *group_start += group_len * beat;
+
+                        Some((start, start + group_len * beat))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .flat_map(|(start, end)| {
+                let pickup = Some(end - beat / 2).filter(|_| rng.gen_bool(0.4));
+
+                [Some(start), pickup]
+            })
+            .flatten()
+            .chain(accents.iter().map(|a| a.rem_euclid(len)))
+            .collect::<Vec<_>>();
+        attacks.sort();
+        attacks.dedup();
+
+        let notes = attacks
+            .iter()
+            .enumerate()
+            .map(|(idx, start)| {
+                let end = attacks.get(idx + 1).copied().unwrap_or(len);
+                let degree = if start % bar == 0 {
+                    RiffDegree::Root
+                } else {
+                    *[
+                        RiffDegree::Root,
+                        RiffDegree::Fifth,
+                        RiffDegree::Fifth,
+                        RiffDegree::Octave,
+                        RiffDegree::FlatSeventh,
+                    ]
+                    .choose(rng)
+                    .unwrap()
+                };
+
+                (degree, *start..end)
+            })
+            .collect();
+
+        Riff { notes, len }
+    }
+}
+
+/// Shared harmonic context for generating bass lines note by note, as opposed to through
+/// [`MelodyDirective`](crate::melody::MelodyDirective)s.
+pub struct BassLine<'a> {
+    pub key: &'a Key,
+    pub chords: Vec<(Range<i32>, &'a Chord)>,
+    /// Times (typically bar and phrase starts) which should land on a chord tone.
+    pub anchors: Vec<i32>,
+    pub range: RangeInclusive<Note>,
+    pub beat: i32,
+}
+
+impl<'a> BassLine<'a> {
+    fn chord_at(&self, time: i32) -> Option<(Range<i32>, &'a Chord)> {
+        self.chords
+            .iter()
+            .find(|(timing, _)| timing.contains(&time))
+            .cloned()
+    }
+
+    fn chord_tones(&self, chord: &Chord) -> Vec<Note> {
+        chord.iter_notes_in_range(self.range.clone()).collect()
+    }
+
+    fn roots(&self, chord: &Chord) -> Vec<Note> {
+        chord.root().notes_in_range(self.range.clone())
+    }
+
+    /// Chooses the candidate closest to `to`, or a random one if there is no `to`.
+    fn nearest(candidates: &[Note], to: Option<Note>, rng: &mut impl Rng) -> Option<Note> {
+        match to {
+            Some(to) => candidates
+                .iter()
+                .min_by_key(|n| (n.0 as i32 - to.0 as i32).abs())
+                .copied(),
+            None => candidates.choose(rng).copied(),
+        }
+    }
+
+    /// A walking line with one note per beat.
+    pub fn walking(&self, timing: Range<i32>, rng: &mut impl Rng) -> Vec<(Note, Range<i32>)> {
+        let scale = self.key.notes_in_range(self.range.clone());
+        let mut line: Vec<(Note, Range<i32>)> = vec![];
+
+        for start in (timing.start..timing.end).step_by(self.beat.max(1) as usize) {
+            let end = (start + self.beat).min(timing.end);
+            let Some((chord_timing, chord)) = self.chord_at(start) else {
+                continue;
+            };
+            let prev = line.last().map(|(n, _)| *n);
+            let next_root = self
+                .chord_at(chord_timing.end)
+                .and_then(|(_, next)| Self::nearest(&self.roots(next), prev, rng));
+
+            let note = if start == chord_timing.start {
+                Self::nearest(&self.roots(chord), prev, rng)
+            } else if self.anchors.contains(&start) {
+                Self::nearest(&self.chord_tones(chord), prev, rng)
+            } else if let Some(target) = next_root.filter(|_| end >= chord_timing.end) {
+                self.approach(target, prev, &scale, rng)
+            } else {
+                let target = next_root.or(prev);
+                match (prev, target) {
+                    (Some(prev), Some(target)) if prev != target => {
+                        let step = if target > prev {
+                            scale.iter().find(|n| **n > prev)
+                        } else {
+                            scale.iter().rev().find(|n| **n < prev)
+                        };
+                        step.copied()
+                    }
+                    _ => {
+                        let others = self
+                            .chord_tones(chord)
+                            .into_iter()
+                            .filter(|n| Some(*n) != prev)
+                            .collect::<Vec<_>>();
+                        Self::nearest(&others, prev, rng)
+                    }
+                }
+            };
+
+            if let Some(note) = note {
+                line.push((note, start..end));
+            }
+        }
+
+        line
+    }
+
+    /// A note leading into `target`, either a half step away or the neighboring scale note, from
+    /// the side of the previous note when possible.
+    fn approach(
+        &self,
+        target: Note,
+        prev: Option<Note>,
+        scale: &[Note],
+        rng: &mut impl Rng,
+    ) -> Option<Note> {
+        let from_below = prev
+            .map(|p| p < target)
+            .unwrap_or_else(|| rng.gen_bool(0.5));
+        let chromatic = if from_below {
+            [Note(target.0.saturating_sub(1)), Note(target.0 + 1)]
+        } else {
+            [Note(target.0 + 1), Note(target.0.saturating_sub(1))]
+        };
+        let diatonic = if from_below {
+            [
+                scale.iter().rev().find(|n| **n < target).copied(),
+                scale.iter().find(|n| **n > target).copied(),
+            ]
+        } else {
+            [
+                scale.iter().find(|n| **n > target).copied(),
+                scale.iter().rev().find(|n| **n < target).copied(),
+            ]
+        };
+
+        let candidates = if rng.gen_bool(0.5) {
+            chromatic
+                .into_iter()
+                .map(Some)
+                .chain(diatonic)
+                .collect::<Vec<_>>()
+        } else {
+            diatonic
+                .into_iter()
+                .chain(chromatic.into_iter().map(Some))
+                .collect::<Vec<_>>()
+        };
+
+        candidates
+            .into_iter()
+            .flatten()
+            .find(|n| self.range.contains(n))
+    }
+
+    /// A line attacking only at the given kick drum positions, which should be straight timings
+    /// prior to swing and humanization so that they line up with chord changes.
+    pub fn kick_locked(
+        &self,
+        kicks: &[i32],
+        timing: Range<i32>,
+        rng: &mut impl Rng,
+    ) -> Vec<(Note, Range<i32>)> {
+        let scale = self.key.notes_in_range(self.range.clone());
+        let mut kicks = kicks
+            .iter()
+            .copied()
+            .filter(|k| timing.contains(k))
+            .collect::<Vec<_>>();
+        kicks.sort();
+        kicks.dedup();
+
+        let mut line: Vec<(Note, Range<i32>)> = vec![];
+        for (idx, start) in kicks.iter().enumerate() {
+            let Some((chord_timing, chord)) = self.chord_at(*start) else {
+                continue;
+            };
+            let next_kick = kicks.get(idx + 1).copied().unwrap_or(timing.end);
+            let end = next_kick.min(chord_timing.end);
+            let prev = line.last().map(|(n, _)| *n);
+            let first_in_chord = line
+                .last()
+                .map(|(_, t)| t.start < chord_timing.start)
+                .unwrap_or(true);
+
+            let candidates = if first_in_chord {
+                self.roots(chord)
+            } else {
+                self.chord_tones(chord)
+                    .into_iter()
+                    .filter(|n| {
+                        let interval = chord.root().interval_to(&n.pitch_class());
+                        interval == Interval::P1 || interval == Interval::P5
+                    })
+                    .collect()
+            };
+            let Some(note) = Self::nearest(&candidates, prev, rng) else {
+                continue;
+            };
+
+            let split = (end - start) / 2;
+            if end - start >= self.beat && split > 0 && rng.gen_bool(0.4) {
+                let octave = [Note(note.0 + 12), Note(note.0.saturating_sub(12))]
+                    .into_iter()
+                    .find(|n| self.range.contains(n));
+                let passing = self
+                    .chord_at(end)
+                    .and_then(|(_, next)| Self::nearest(&self.roots(next), Some(note), rng))
+                    .filter(|target| *target != note)
+                    .and_then(|target| {
+                        if target > note {
+                            scale.iter().find(|n| **n > note).copied()
+                        } else {
+                            scale.iter().rev().find(|n| **n < note).copied()
+                        }
+                    });
+                let second = if rng.gen_bool(0.5) {
+                    octave.or(passing)
+                } else {
+                    passing.or(octave)
+                };
+
+                if let Some(second) = second {
+                    line.push((note, *start..(start + split)));
+                    line.push((second, (start + split)..end));
+                    continue;
+                }
+            }
+
+            line.push((note, *start..end));
+        }
+
+        line
+    }
+
+    /// Repeats `riff` from `origin` across `timing`, transposing each note to the chord sounding at
+    /// its start.
+    pub fn riff(&self, riff: &Riff, origin: i32, timing: Range<i32>) -> Vec<(Note, Range<i32>)> {
+        if riff.len <= 0 {
+            return vec![];
+        }
+
+        let first_repeat = origin + (timing.start - origin).div_euclid(riff.len) * riff.len;
+        let mut prev_root: Option<Note> = None;
+
+        (first_repeat..timing.end)
+            .step_by(riff.len as usize)
+            .flat_map(|repeat_start| {
+                riff.notes.iter().map(move |(degree, t)| {
+                    (*degree, (repeat_start + t.start)..(repeat_start + t.end))
+                })
+            })
+            .filter(|(_, t)| timing.contains(&t.start))
+            .flat_map(|(degree, t)| {
+                let (chord_timing, chord) = self.chord_at(t.start)?;
+                let root = self
+                    .roots(chord)
+                    .into_iter()
+                    .min_by_key(|n| match prev_root {
+                        Some(prev) => (n.0 as i32 - prev.0 as i32).abs(),
+                        None => (n.0 as i32 - self.range.start().0 as i32 - 7).abs(),
+                    })?;
+                prev_root = Some(root);
+
+                let fifth = [Note(root.0 + 7), Note(root.0.saturating_sub(5))]
+                    .into_iter()
+                    .find(|n| self.range.contains(n));
+                let note = match degree {
+                    RiffDegree::Root => Some(root),
+                    RiffDegree::Fifth => fifth,
+                    RiffDegree::Octave => [Note(root.0 + 12), Note(root.0.saturating_sub(12))]
+                        .into_iter()
+                        .find(|n| self.range.contains(n)),
+                    RiffDegree::FlatSeventh => Some(chord.root() + Interval::m7)
+                        .filter(|pc| self.key.contains(pc))
+                        .map(|_| Note(root.0 + 10))
+                        .filter(|n| self.range.contains(n))
+                        .or(fifth),
+                }
+                .unwrap_or(root);
+
+                Some((note, t.start..t.end.min(chord_timing.end).min(timing.end)))
+            })
+            .collect()
+    }
+}
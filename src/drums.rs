@@ -0,0 +1,506 @@
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use redact_composer::midi::gm::DrumHitType;
+use redact_composer::Element;
+use serde::{Deserialize, Serialize};
+use std::ops::{Range, RangeInclusive};
+
+/// The function a drum hit serves within a groove.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DrumRole {
+    /// The low end anchoring the groove (kick drums).
+    Kick,
+    /// Steady subdivisions (hi-hats, ride, shakers).
+    Timekeeper,
+    /// Weak-beat hits (snares, claps, rim-shots).
+    Backbeat,
+    /// Emphasis on important downbeats (crashes).
+    Accent,
+    /// Occasional color (toms, tambourine, congas, ride bell).
+    Colour,
+}
+
+impl DrumRole {
+    pub fn of(hit: DrumHitType) -> Option<DrumRole> {
+        match hit {
+            DrumHitType::AcousticBassDrum | DrumHitType::BassDrum1 => Some(DrumRole::Kick),
+            DrumHitType::ClosedHiHat
+            | DrumHitType::PedalHiHat
+            | DrumHitType::OpenHiHat
+            | DrumHitType::RideCymbal1
+            | DrumHitType::RideCymbal2
+            | DrumHitType::Maracas
+            | DrumHitType::Cabasa => Some(DrumRole::Timekeeper),
+            DrumHitType::AcousticSnare
+            | DrumHitType::ElectricSnare
+            | DrumHitType::SideStick
+            | DrumHitType::HandClap => Some(DrumRole::Backbeat),
+            DrumHitType::CrashCymbal1 | DrumHitType::CrashCymbal2 | DrumHitType::SplashCymbal => {
+                Some(DrumRole::Accent)
+            }
+            DrumHitType::RideBell
+            | DrumHitType::Tambourine
+            | DrumHitType::LowFloorTom
+            | DrumHitType::HighFloorTom
+            | DrumHitType::LowTom
+            | DrumHitType::LowMidTom
+            | DrumHitType::HiMidTom
+            | DrumHitType::HighTom
+            | DrumHitType::MuteHiConga
+            | DrumHitType::OpenHiConga
+            | DrumHitType::LowConga => Some(DrumRole::Colour),
+            _ => None,
+        }
+    }
+}
+
+/// A coherent subset of drum hits used together in a groove, with one choice per role.
+#[derive(Debug, Clone)]
+pub struct DrumVoices {
+    pub kick: DrumHitType,
+    pub backbeat: DrumHitType,
+    /// The main timekeeper, followed by a secondary one played less often.
+    pub timekeepers: [DrumHitType; 2],
+    pub accent: DrumHitType,
+    pub colours: Vec<DrumHitType>,
+}
+
+impl DrumVoices {
+    pub fn random(rng: &mut impl Rng) -> DrumVoices {
+        let backbeat = *[
+            DrumHitType::AcousticSnare,
+            DrumHitType::AcousticSnare,
+            DrumHitType::ElectricSnare,
+            DrumHitType::SideStick,
+            DrumHitType::HandClap,
+        ]
+        .choose(rng)
+        .unwrap();
+        let timekeepers = *[
+            [DrumHitType::ClosedHiHat, DrumHitType::PedalHiHat],
+            [DrumHitType::ClosedHiHat, DrumHitType::PedalHiHat],
+            [DrumHitType::ClosedHiHat, DrumHitType::OpenHiHat],
+            [DrumHitType::RideCymbal1, DrumHitType::RideBell],
+            [DrumHitType::RideCymbal1, DrumHitType::PedalHiHat],
+            [DrumHitType::Maracas, DrumHitType::Cabasa],
+        ]
+        .choose(rng)
+        .unwrap();
+        let accent = *[DrumHitType::CrashCymbal1, DrumHitType::CrashCymbal2]
+            .choose(rng)
+            .unwrap();
+        let colours = [
+            vec![],
+            vec![DrumHitType::Tambourine],
+            vec![
+                DrumHitType::MuteHiConga,
+                DrumHitType::OpenHiConga,
+                DrumHitType::LowConga,
+            ],
+            vec![DrumHitType::HighTom, DrumHitType::LowTom],
+        ]
+        .choose(rng)
+        .unwrap()
+        .clone();
+
+        DrumVoices {
+            kick: DrumHitType::AcousticBassDrum,
+            backbeat,
+            timekeepers,
+            accent,
+            colours,
+        }
+    }
+
+    pub fn voice(&self, voice: GrooveVoice) -> Option<DrumHitType> {
+        match voice {
+            GrooveVoice::Kick => Some(self.kick),
+            GrooveVoice::Backbeat => Some(self.backbeat),
+            GrooveVoice::Timekeeper => Some(self.timekeepers[0]),
+            GrooveVoice::SecondaryTimekeeper => Some(self.timekeepers[1]),
+            GrooveVoice::Accent => Some(self.accent),
+            GrooveVoice::Colour => self.colours.first().copied(),
+        }
+    }
+
+    /// Hits available for free rhythm generation, weighted by how often they should be played.
+    pub fn weighted_hits(&self) -> Vec<(DrumHitType, i32)> {
+        [
+            (self.kick, 1),
+            (self.backbeat, 1),
+            (self.timekeepers[0], 8),
+            (self.timekeepers[1], 4),
+        ]
+        .into_iter()
+        .chain(self.colours.iter().map(|c| (*c, 1)))
+        .collect()
+    }
+}
+
+/// A drum hit planned by a groove, prior to dynamics, swing and humanization.
+#[derive(Debug, Clone)]
+pub struct DrumNote {
+    pub hit: DrumHitType,
+    pub timing: Range<i32>,
+    /// End of the phrase containing this hit.
+    pub phrase_end: i32,
+    /// Added to the velocity derived from dynamics.
+    pub velocity_offset: i32,
+    pub layer: DrumLayer,
+}
+
+/// The dynamic layer a [`DrumNote`] belongs to, each with its own velocity range.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DrumLayer {
+    Ghost,
+    Groove,
+    /// Notes of a fill, which rush toward its end.
+    Fill,
+    Accent,
+}
+
+impl DrumLayer {
+    fn range(&self) -> RangeInclusive<i32> {
+        match self {
+            DrumLayer::Ghost => 18..=45,
+            DrumLayer::Groove | DrumLayer::Fill => 45..=112,
+            DrumLayer::Accent => 75..=127,
+        }
+    }
+
+    /// Scales a velocity derived from dynamics into this layer's range.
+    pub fn velocity(&self, velocity: i32) -> u8 {
+        let range = self.range();
+        let scaled = range.start() + velocity.clamp(0, 127) * (range.end() - range.start()) / 127;
+
+        scaled.clamp(1, 127) as u8
+    }
+}
+
+/// The steps a drum part's phrases are played on.
+#[derive(Debug, Copy, Clone)]
+pub struct DrumGrid<'a> {
+    pub bar_origin: i32,
+    pub beat: i32,
+    pub steps_per_beat: i32,
+    pub phrases: &'a [Range<i32>],
+}
+
+/// Ghost notes and hi-hat articulation layered over a groove.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct DrumDynamics {
+    pub density: f32,
+}
+
+impl Default for DrumDynamics {
+    fn default() -> Self {
+        Self { density: 0.15 }
+    }
+}
+
+impl DrumDynamics {
+    pub fn random(rng: &mut impl Rng) -> DrumDynamics {
+        DrumDynamics {
+            density: rng.gen_range(0.0..=0.4),
+        }
+    }
+
+    /// Layers ghost notes, hi-hat accents and open hi-hats over `hits`, on the `grid`'s steps
+    /// within its phrases.
+    pub fn layer(
+        &self,
+        hits: &mut Vec<DrumNote>,
+        voices: &DrumVoices,
+        grid: &DrumGrid,
+        rng: &mut impl Rng,
+    ) {
+        let DrumGrid {
+            bar_origin,
+            beat,
+            steps_per_beat,
+            phrases,
+        } = *grid;
+        let density = self.density.clamp(0.0, 1.0) as f64;
+        let step = (beat / steps_per_beat.max(1)).max(1);
+        let on_beat = |time: i32| (time - bar_origin).rem_euclid(beat) == 0;
+        let weak = |time: i32| {
+            let idx = (time - bar_origin).rem_euclid(beat) / step;
+            let offbeat = if steps_per_beat == 3 {
+                2
+            } else {
+                steps_per_beat / 2
+            };
+
+            idx != 0 && idx != offbeat
+        };
+
+        // Timekeepers on the beat are accented
+        for hit in hits.iter_mut() {
+            if hit.hit == voices.timekeepers[0] && on_beat(hit.timing.start) {
+                hit.layer = DrumLayer::Accent;
+            }
+        }
+
+        // The hi-hat opens on the step leading into each backbeat
+        if voices.timekeepers[0] == DrumHitType::ClosedHiHat {
+            let backbeats = hits
+                .iter()
+                .filter(|h| h.hit == voices.backbeat && on_beat(h.timing.start))
+                .map(|h| h.timing.start)
+                .collect::<Vec<_>>();
+
+            for backbeat in backbeats {
+                let time = backbeat - step;
+                let Some(phrase) = phrases.iter().find(|p| p.contains(&time)) else {
+                    continue;
+                };
+                if !rng.gen_bool(density) {
+                    continue;
+                }
+
+                hits.retain(|h| {
+                    h.timing.start != time || DrumRole::of(h.hit) != Some(DrumRole::Timekeeper)
+                });
+                hits.push(DrumNote {
+                    hit: DrumHitType::OpenHiHat,
+                    timing: time..backbeat,
+                    phrase_end: phrase.end,
+                    velocity_offset: 0,
+                    layer: DrumLayer::Groove,
+                });
+            }
+        }
+
+        // Ghost notes fill in weak steps not already played by the snare
+        let ghost = match voices.backbeat {
+            DrumHitType::AcousticSnare | DrumHitType::ElectricSnare => voices.backbeat,
+            _ => DrumHitType::AcousticSnare,
+        };
+        for phrase in phrases {
+            let first_step =
+                bar_origin + (phrase.start - bar_origin + step - 1).div_euclid(step) * step;
+
+            for time in (first_step..phrase.end).step_by(step as usize) {
+                let taken = hits.iter().any(|h| {
+                    h.timing.start == time && (h.hit == ghost || h.hit == voices.backbeat)
+                });
+                if !weak(time) || taken || !rng.gen_bool(density) {
+                    continue;
+                }
+
+                hits.push(DrumNote {
+                    hit: ghost,
+                    timing: time..(time + step).min(phrase.end),
+                    phrase_end: phrase.end,
+                    velocity_offset: 0,
+                    layer: DrumLayer::Ghost,
+                });
+            }
+        }
+    }
+}
+
+/// The [`DrumVoices`] a [`GrooveLane`] plays.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum GrooveVoice {
+    Kick,
+    Backbeat,
+    Timekeeper,
+    SecondaryTimekeeper,
+    Accent,
+    Colour,
+}
+
+/// A single voice's pattern within a [`GrooveTemplate`], with one character per step: `x` for a
+/// hit and anything else for a rest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrooveLane {
+    pub voice: GrooveVoice,
+    pub pattern: String,
+}
+
+/// A one bar drum pattern in a recognizable style.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrooveTemplate {
+    pub name: String,
+    pub beats_per_bar: i32,
+    pub steps_per_beat: i32,
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+    pub lanes: Vec<GrooveLane>,
+}
+
+impl GrooveTemplate {
+    /// The built in groove templates, skipping any whose lanes don't span exactly one bar.
+    pub fn library() -> Vec<GrooveTemplate> {
+        serde_json::from_str::<Vec<GrooveTemplate>>(include_str!("grooves.json"))
+            .expect("Built in groove templates should be valid")
+            .into_iter()
+            .filter(|template| {
+                let steps = (template.beats_per_bar * template.steps_per_beat) as usize;
+                template
+                    .lanes
+                    .iter()
+                    .all(|lane| lane.pattern.len() == steps)
+            })
+            .collect()
+    }
+
+    /// Chooses a template matching the meter and tempo, if any, from those named in `allowed` (or
+    /// any if empty).
+    pub fn choose(
+        beats_per_bar: i32,
+        bpm: f32,
+        allowed: &[String],
+        rng: &mut impl Rng,
+    ) -> Option<GrooveTemplate> {
+        let candidates = Self::library()
+            .into_iter()
+            .filter(|t| allowed.is_empty() || allowed.contains(&t.name))
+            .filter(|t| t.beats_per_bar == beats_per_bar)
+            .filter(|t| (t.min_bpm..=t.max_bpm).contains(&bpm))
+            .collect::<Vec<_>>();
+
+        candidates
+            .choose(rng)
+            .filter(|_| rng.gen_bool(0.85))
+            .cloned()
+    }
+
+    /// Plays the template over each phrase, aligned to bars starting from `bar_origin`.
+    pub fn hits(
+        &self,
+        voices: &DrumVoices,
+        bar_origin: i32,
+        beat: i32,
+        phrases: &[Range<i32>],
+        rng: &mut impl Rng,
+    ) -> Vec<DrumNote> {
+        let step = (beat / self.steps_per_beat.max(1)).max(1);
+        let steps = self.beats_per_bar * self.steps_per_beat;
+        let bar = beat * self.beats_per_bar;
+
+        phrases
+            .iter()
+            .flat_map(|phrase| {
+                let first_step =
+                    bar_origin + (phrase.start - bar_origin + step - 1).div_euclid(step) * step;
+
+                (first_step..phrase.end)
+                    .step_by(step as usize)
+                    .flat_map(|time| {
+                        let idx = (time - bar_origin).div_euclid(step).rem_euclid(steps) as usize;
+                        let downbeat = (time - bar_origin).rem_euclid(bar) == 0;
+
+                        let mut hits = self
+                            .lanes
+                            .iter()
+                            .filter(|lane| lane.pattern.as_bytes()[idx] == b'x')
+                            .flat_map(|lane| voices.voice(lane.voice))
+                            .filter(|hit| (downbeat && *hit == voices.kick) || !rng.gen_bool(0.06))
+                            .collect::<Vec<_>>();
+
+                        if downbeat && !hits.contains(&voices.kick) {
+                            hits.push(voices.kick);
+                        } else if !hits.contains(&voices.kick) && idx % 2 == 0 && rng.gen_bool(0.03)
+                        {
+                            hits.push(voices.kick);
+                        }
+
+                        hits.into_iter()
+                            .map(|hit| DrumNote {
+                                hit,
+                                timing: time..(time + step).min(phrase.end),
+                                phrase_end: phrase.end,
+                                velocity_offset: 0,
+                                layer: DrumLayer::Groove,
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// How important the boundary at the end of a phrase is, which determines how often and how long
+/// drum fills leading into it are.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Boundary {
+    Section,
+    Bar,
+    Phrase,
+}
+
+impl Boundary {
+    fn fill_probability(&self) -> f64 {
+        match self {
+            Boundary::Section => 0.9,
+            Boundary::Bar => 0.35,
+            Boundary::Phrase => 0.1,
+        }
+    }
+
+    fn fill_len(&self, beat: i32, bar: i32, rng: &mut impl Rng) -> i32 {
+        match self {
+            Boundary::Section => bar,
+            Boundary::Bar => beat * rng.gen_range(1..=2),
+            Boundary::Phrase => beat,
+        }
+    }
+
+    /// Plans a fill leading into this boundary at `phrase.end`, or nothing if this boundary is
+    /// randomly skipped.
+    pub fn fill(
+        &self,
+        voices: &DrumVoices,
+        phrase: Range<i32>,
+        beat: i32,
+        bar: i32,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<DrumNote>> {
+        if !rng.gen_bool(self.fill_probability()) {
+            return None;
+        }
+
+        let start = (phrase.end - self.fill_len(beat, bar, rng)).max(phrase.start);
+        let len = phrase.end - start;
+        let toms = [
+            DrumHitType::HighTom,
+            DrumHitType::HiMidTom,
+            DrumHitType::LowMidTom,
+            DrumHitType::LowTom,
+            DrumHitType::HighFloorTom,
+            DrumHitType::LowFloorTom,
+        ];
+
+        // Density and velocity rise through the fill, while toms descend
+        let mut notes = vec![];
+        let mut time = start;
+        while time < phrase.end {
+            let progress = (time - start) as f32 / len as f32;
+            let step = if progress < 0.5 && len > beat {
+                beat / 2
+            } else {
+                beat / 4
+            }
+            .max(1);
+            let hit = if rng.gen_bool(0.3) {
+                voices.backbeat
+            } else {
+                toms[((progress * toms.len() as f32) as usize).min(toms.len() - 1)]
+            };
+
+            notes.push(DrumNote {
+                hit,
+                timing: time..(time + step).min(phrase.end),
+                phrase_end: phrase.end,
+                velocity_offset: (-10.0 + 25.0 * progress) as i32,
+                layer: DrumLayer::Fill,
+            });
+            time += step;
+        }
+
+        Some(notes)
+    }
+}
@@ -0,0 +1,189 @@
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use redact_composer::error::RendererError;
+use redact_composer::musical::elements::TimeSignature;
+use redact_composer::render::context::CompositionContext;
+use redact_composer::render::context::TimingRelation::{During, Overlapping};
+use redact_composer::Element;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Dynamic markings, from pianissimo to fortissimo.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum DynamicMarking {
+    Pianissimo,
+    Piano,
+    MezzoPiano,
+    MezzoForte,
+    Forte,
+    Fortissimo,
+}
+
+impl DynamicMarking {
+    pub fn values() -> Vec<DynamicMarking> {
+        vec![
+            DynamicMarking::Pianissimo,
+            DynamicMarking::Piano,
+            DynamicMarking::MezzoPiano,
+            DynamicMarking::MezzoForte,
+            DynamicMarking::Forte,
+            DynamicMarking::Fortissimo,
+        ]
+    }
+
+    /// The velocity this marking centers around.
+    pub fn velocity(&self) -> i32 {
+        match self {
+            DynamicMarking::Pianissimo => 40,
+            DynamicMarking::Piano => 55,
+            DynamicMarking::MezzoPiano => 70,
+            DynamicMarking::MezzoForte => 85,
+            DynamicMarking::Forte => 100,
+            DynamicMarking::Fortissimo => 112,
+        }
+    }
+}
+
+/// The section-level dynamic marking.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Dynamics {
+    pub marking: DynamicMarking,
+}
+
+impl Dynamics {
+    /// Chooses a marking, favoring the middle of the dynamic range.
+    pub fn random(rng: &mut impl Rng) -> Dynamics {
+        let marking = DynamicMarking::values()
+            .into_iter()
+            .zip([1, 3, 5, 6, 4, 1])
+            .collect::<Vec<_>>()
+            .choose_weighted(rng, |(_, w)| *w)
+            .map(|(m, _)| *m)
+            .unwrap_or(DynamicMarking::MezzoForte);
+
+        Dynamics { marking }
+    }
+}
+
+/// The dynamic shape across a [`PhraseDivider`](crate::structure::PhraseDivider).
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Hairpin {
+    Crescendo,
+    Decrescendo,
+    /// Crescendo into the middle of the phrase, then decrescendo.
+    Swell,
+    Flat,
+}
+
+impl Hairpin {
+    pub fn random(rng: &mut impl Rng) -> Hairpin {
+        *[
+            Hairpin::Crescendo,
+            Hairpin::Decrescendo,
+            Hairpin::Swell,
+            Hairpin::Swell,
+            Hairpin::Flat,
+        ]
+        .choose(rng)
+        .unwrap()
+    }
+
+    /// Velocity offset at `progress` (`0.0..=1.0`) through the phrase.
+    fn offset(&self, progress: f32) -> f32 {
+        match self {
+            Hairpin::Crescendo => -10.0 + 20.0 * progress,
+            Hairpin::Decrescendo => 10.0 - 20.0 * progress,
+            Hairpin::Swell => -8.0 + 16.0 * (1.0 - (2.0 * progress - 1.0).abs()),
+            Hairpin::Flat => 0.0,
+        }
+    }
+}
+
+/// Derives note velocities from the [`Dynamics`] marking, [`Hairpin`]s and metric position of
+/// notes within a span.
+pub struct VelocityShaper {
+    marking: DynamicMarking,
+    bar_start: i32,
+    bar: i32,
+    beat: i32,
+    half_beat: i32,
+    beats_per_bar: i32,
+    hairpins: Vec<(Range<i32>, Hairpin)>,
+}
+
+impl VelocityShaper {
+    pub fn from_context(
+        ctx: &CompositionContext,
+        timing: Range<i32>,
+    ) -> Result<VelocityShaper, RendererError> {
+        let ts = ctx
+            .find::<TimeSignature>()
+            .with_timing(During, timing.clone())
+            .require()?;
+        let marking = ctx
+            .find::<Dynamics>()
+            .with_timing(During, timing.clone())
+            .get()
+            .map(|dynamics| dynamics.element.marking)
+            .unwrap_or(DynamicMarking::MezzoForte);
+        let hairpins = ctx
+            .find::<Hairpin>()
+            .with_timing(Overlapping, timing)
+            .get_all()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|hairpin| (hairpin.timing.start..hairpin.timing.end, *hairpin.element))
+            .collect();
+
+        Ok(VelocityShaper {
+            marking,
+            bar_start: ts.timing.start,
+            bar: ts.element.bar(),
+            beat: ts.element.beat(),
+            half_beat: ts.element.half_beat(),
+            beats_per_bar: ts.element.beats_per_bar,
+            hairpins,
+        })
+    }
+
+    /// Velocity offset from the position of `time` within its bar: downbeats are loudest,
+    /// followed by the middle of evenly divided bars, other beats, offbeats and finally smaller
+    /// subdivisions.
+    fn metric_accent(&self, time: i32) -> f32 {
+        let position = (time - self.bar_start).rem_euclid(self.bar);
+
+        if position == 0 {
+            12.0
+        } else if position % self.beat == 0 {
+            if self.beats_per_bar % 2 == 0 && position == self.bar / 2 {
+                6.0
+            } else {
+                2.0
+            }
+        } else if position % self.half_beat == 0 {
+            -4.0
+        } else {
+            -8.0
+        }
+    }
+
+    fn hairpin(&self, time: i32) -> f32 {
+        self.hairpins
+            .iter()
+            .find(|(timing, _)| timing.contains(&time))
+            .map(|(timing, hairpin)| {
+                hairpin.offset((time - timing.start) as f32 / (timing.end - timing.start) as f32)
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Velocity for a note starting at `time`, with slight random variation.
+    pub fn velocity(&self, time: i32, rng: &mut impl Rng) -> u8 {
+        let velocity = self.marking.velocity() as f32
+            + self.metric_accent(time)
+            + self.hairpin(time)
+            + rng.gen_range(-4.0..=4.0);
+
+        velocity.round().clamp(1.0, 127.0) as u8
+    }
+}
@@ -0,0 +1,405 @@
+use crate::style::Style;
+use rand::prelude::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use redact_composer::elements::Part;
+use redact_composer::error::RendererError;
+use redact_composer::musical::elements::TimeSignature;
+use redact_composer::render::context::CompositionContext;
+use redact_composer::render::context::TimingRelation::During;
+use redact_composer::render::{AdhocRenderer, RenderEngine};
+use redact_composer::util::IntoSegment;
+use redact_composer::{Element, Renderer};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+pub fn renderers() -> RenderEngine {
+    RenderEngine::new() + RandomSwing::renderer()
+}
+
+/// The subdivision of the [`TimeSignature`] a [`Swing`] applies to.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SwingSubdivision {
+    Eighths,
+    Sixteenths,
+}
+
+/// Delays every other note of a subdivision.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Swing {
+    pub ratio: f32,
+    pub subdivision: SwingSubdivision,
+}
+
+impl Swing {
+    /// Looks up the [`Swing`] during `timing`, returning a [`SwingGrid`] aligned with the bars of
+    /// the current [`TimeSignature`].
+    pub fn grid(ctx: &CompositionContext, timing: Range<i32>) -> Result<SwingGrid, RendererError> {
+        let ts = ctx
+            .find::<TimeSignature>()
+            .with_timing(During, timing.clone())
+            .require()?;
+        let swing = ctx
+            .find::<Swing>()
+            .with_timing(During, timing)
+            .get()
+            .map(|swing| *swing.element);
+
+        Ok(match swing {
+            Some(swing) => SwingGrid {
+                ratio: swing.ratio.clamp(0.5, 0.75),
+                unit: match swing.subdivision {
+                    SwingSubdivision::Eighths => ts.element.half_beat(),
+                    SwingSubdivision::Sixteenths => ts.element.quarter_beat(),
+                },
+                origin: ts.timing.start,
+            },
+            None => SwingGrid {
+                ratio: 0.5,
+                unit: ts.element.half_beat(),
+                origin: ts.timing.start,
+            },
+        })
+    }
+}
+
+/// Maps straight timings onto swung ones.
+#[derive(Debug, Copy, Clone)]
+pub struct SwingGrid {
+    ratio: f32,
+    unit: i32,
+    origin: i32,
+}
+
+impl SwingGrid {
+    pub fn apply(&self, time: i32) -> i32 {
+        if self.ratio <= 0.5 || self.unit <= 0 {
+            return time;
+        }
+
+        let pair = self.unit * 2;
+        let relative = time - self.origin;
+        let pair_start = relative.div_euclid(pair) * pair;
+        let position = relative - pair_start;
+        let swung_offbeat = (pair as f32 * self.ratio).round() as i32;
+
+        let swung_position = if position <= self.unit {
+            position * swung_offbeat / self.unit
+        } else {
+            swung_offbeat + (position - self.unit) * (pair - swung_offbeat) / self.unit
+        };
+
+        self.origin + pair_start + swung_position
+    }
+
+    pub fn apply_range(&self, timing: Range<i32>) -> Range<i32> {
+        self.apply(timing.start)..self.apply(timing.end)
+    }
+
+    /// Steps per beat for grooves without a template of their own: swung eighths fall on a
+    /// triplet grid, while other beats divide into sixteenths.
+    pub fn steps_per_beat(&self, beat: i32) -> i32 {
+        if self.ratio > 0.5 && self.unit * 2 == beat {
+            3
+        } else {
+            4
+        }
+    }
+
+    /// Finds the straight timing, on a grid of any of the `steps` from the origin, which this
+    /// swings closest to `time`.
+    pub fn straighten(&self, time: i32, steps: &[i32]) -> i32 {
+        steps
+            .iter()
+            .filter(|step| **step > 0)
+            .flat_map(|step| {
+                let nearest = (time - self.origin) as f32 / *step as f32;
+                let nearest = self.origin + nearest.round() as i32 * step;
+
+                [nearest - step, nearest, nearest + step]
+            })
+            .min_by_key(|straight| ((self.apply(*straight) - time).abs(), *straight))
+            .unwrap_or(time)
+    }
+}
+
+#[derive(Element, Serialize, Deserialize, Debug)]
+pub struct RandomSwing;
+
+impl RandomSwing {
+    pub fn renderer() -> impl Renderer<Element = Self> {
+        AdhocRenderer::<Self>::new(|segment, ctx| {
+            let mut rng = ctx.rng();
+            let style = Style::during(ctx, segment.timing.start..segment.timing.end);
+
+            if !rng.gen_bool(style.swing_probability.clamp(0.0, 1.0)) {
+                return Ok(vec![]);
+            }
+
+            let subdivision = if rng.gen_bool(0.7) {
+                SwingSubdivision::Eighths
+            } else {
+                SwingSubdivision::Sixteenths
+            };
+
+            Ok(vec![Swing {
+                ratio: rng.gen_range(0.55..=0.72),
+                subdivision,
+            }
+            .over(segment)])
+        })
+    }
+}
+
+/// Seeded, bounded timing and velocity variation applied to notes of the [`Part`] it is placed in.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Humanize {
+    /// Maximum random timing offset in either direction.
+    pub timing_jitter: f32,
+    /// Constant timing offset. Positive values lay back behind the beat, negative values rush.
+    pub lay_back: f32,
+    /// Additional rush applied across the last beat of a phrase, as in drum fills.
+    pub fill_rush: f32,
+    /// Maximum random velocity offset in either direction.
+    pub velocity_jitter: u8,
+}
+
+impl Humanize {
+    pub fn drums() -> Humanize {
+        Humanize {
+            timing_jitter: 0.015,
+            lay_back: 0.0,
+            fill_rush: 0.03,
+            velocity_jitter: 8,
+        }
+    }
+
+    pub fn bass() -> Humanize {
+        Humanize {
+            timing_jitter: 0.02,
+            lay_back: 0.02,
+            fill_rush: 0.0,
+            velocity_jitter: 6,
+        }
+    }
+
+    pub fn lead() -> Humanize {
+        Humanize {
+            timing_jitter: 0.03,
+            lay_back: 0.01,
+            fill_rush: 0.0,
+            velocity_jitter: 10,
+        }
+    }
+
+    /// Looks up the [`Humanize`] within the nearest [`Part`] ancestor during `timing`, waiting
+    /// for the part to place it.
+    pub fn of_part(
+        ctx: &CompositionContext,
+        timing: Range<i32>,
+    ) -> Result<Humanize, RendererError> {
+        Ok(*ctx
+            .find::<Humanize>()
+            .within_ancestor::<Part>()
+            .with_timing(During, timing)
+            .require()?
+            .element)
+    }
+
+    /// Applies this profile to notes during `timing`.
+    pub fn humanizer(
+        self,
+        ctx: &CompositionContext,
+        timing: Range<i32>,
+    ) -> Result<Humanizer, RendererError> {
+        let ts = ctx
+            .find::<TimeSignature>()
+            .with_timing(During, timing)
+            .require()?;
+
+        Ok(Humanizer {
+            humanize: self,
+            beat: ts.element.beat(),
+            bar: ts.element.bar(),
+            origin: ts.timing.start,
+        })
+    }
+}
+
+/// Applies a [`Humanize`] profile to individual notes.
+#[derive(Debug, Copy, Clone)]
+pub struct Humanizer {
+    humanize: Humanize,
+    beat: i32,
+    bar: i32,
+    origin: i32,
+}
+
+impl Humanizer {
+    /// Returns the humanized timing and velocity of a note, rushing it toward `phrase_end` if it is
+    /// part of a fill.
+    pub fn apply(
+        &self,
+        timing: Range<i32>,
+        velocity: u8,
+        phrase_end: Option<i32>,
+        rng: &mut impl Rng,
+    ) -> (Range<i32>, u8) {
+        let humanize = self.humanize;
+        let beat = self.beat as f32;
+        let jitter = humanize.timing_jitter.abs() * beat;
+        let fill_progress = phrase_end
+            .filter(|end| end - timing.start <= self.beat)
+            .map(|end| 1.0 - (end - timing.start) as f32 / beat)
+            .unwrap_or(0.0);
+        let mut offset = (rng.gen_range(-jitter..=jitter) + humanize.lay_back * beat
+            - humanize.fill_rush * fill_progress * beat)
+            .round() as i32;
+
+        if (timing.start - self.origin).rem_euclid(self.bar) == 0 {
+            let tolerance = self.beat / 64;
+            offset = offset.clamp(-tolerance, tolerance);
+        }
+        let offset = offset.max(-timing.start);
+
+        let velocity_jitter = humanize.velocity_jitter as i32;
+        let velocity = (velocity as i32 + rng.gen_range(-velocity_jitter..=velocity_jitter))
+            .clamp(1, 127) as u8;
+
+        ((timing.start + offset)..(timing.end + offset), velocity)
+    }
+}
+
+/// The subdivision of the [`TimeSignature`] a [`Polyrhythm`] groups.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum PulseUnit {
+    Eighths,
+    Sixteenths,
+}
+
+impl PulseUnit {
+    pub fn len(&self, ts: &TimeSignature) -> i32 {
+        match self {
+            PulseUnit::Eighths => ts.half_beat(),
+            PulseUnit::Sixteenths => ts.quarter_beat(),
+        }
+    }
+}
+
+/// A cross-rhythm pulsing every `group` [`PulseUnit`]s against the meter, such as dotted quarters
+/// (3 eighths) over 4/4. Pulses restart at the beginning of each
+/// [`PhraseDivider`](crate::structure::PhraseDivider).
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Polyrhythm {
+    pub group: i32,
+    pub unit: PulseUnit,
+    /// Whether [`DrumPart`](crate::parts::DrumPart)s add a voice playing the pulse.
+    pub drums: bool,
+    /// Whether melodies repeat rhythmic cells of the pulse length.
+    pub melody: bool,
+}
+
+impl Polyrhythm {
+    /// Sometimes chooses a grouping which does not evenly divide the bar, so it cycles against it.
+    pub fn random(ts: &TimeSignature, rng: &mut impl Rng) -> Option<Polyrhythm> {
+        if !rng.gen_bool(0.25) {
+            return None;
+        }
+
+        let unit = if rng.gen_bool(0.6) {
+            PulseUnit::Eighths
+        } else {
+            PulseUnit::Sixteenths
+        };
+        let units_per_bar = ts.bar() / unit.len(ts).max(1);
+        let group = [3, 5, 7]
+            .into_iter()
+            .filter(|group| units_per_bar % group != 0 && *group < units_per_bar)
+            .choose(rng)?;
+        let (drums, melody) = *[(true, false), (false, true), (true, true)].choose(rng)?;
+
+        Some(Polyrhythm {
+            group,
+            unit,
+            drums,
+            melody,
+        })
+    }
+
+    pub fn pulse_len(&self, ts: &TimeSignature) -> i32 {
+        self.group.max(1) * self.unit.len(ts)
+    }
+
+    /// Pulse times within `phrase`, starting from its beginning.
+    pub fn pulses(&self, phrase: Range<i32>, ts: &TimeSignature) -> Vec<i32> {
+        (phrase.start..phrase.end)
+            .step_by(self.pulse_len(ts).max(1) as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triplet_swing(origin: i32) -> SwingGrid {
+        SwingGrid {
+            ratio: 2.0 / 3.0,
+            unit: 240,
+            origin,
+        }
+    }
+
+    #[test]
+    fn straight_grid_is_unchanged() {
+        let grid = SwingGrid {
+            ratio: 0.5,
+            unit: 240,
+            origin: 0,
+        };
+
+        for time in [0, 120, 240, 359, 480, 1000] {
+            assert_eq!(grid.apply(time), time);
+        }
+    }
+
+    #[test]
+    fn swing_delays_offbeats() {
+        let grid = triplet_swing(0);
+
+        assert_eq!(grid.apply(0), 0);
+        assert_eq!(grid.apply(240), 320);
+        assert_eq!(grid.apply(480), 480);
+        assert_eq!(grid.apply(720), 800);
+    }
+
+    #[test]
+    fn swing_scales_positions_within_pair() {
+        let grid = triplet_swing(0);
+
+        assert_eq!(grid.apply(120), 160);
+        assert_eq!(grid.apply(360), 400);
+        assert_eq!(grid.apply_range(240..480), 320..480);
+    }
+
+    #[test]
+    fn swing_is_aligned_to_origin() {
+        let grid = triplet_swing(100);
+
+        assert_eq!(grid.apply(100), 100);
+        assert_eq!(grid.apply(340), 420);
+        // Timings before the origin are swung on the same grid
+        assert_eq!(grid.apply(-140), -60);
+    }
+
+    #[test]
+    fn straighten_recovers_planned_timing() {
+        let grid = triplet_swing(0);
+        let steps = [120, 160];
+
+        assert_eq!(grid.straighten(320, &steps), 240);
+        // Slightly rushed or dragged hits snap back to their step
+        assert_eq!(grid.straighten(475, &steps), 480);
+        assert_eq!(grid.straighten(-7, &steps), 0);
+        assert_eq!(grid.straighten(165, &steps), 120);
+    }
+}
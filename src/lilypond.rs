@@ -0,0 +1,259 @@
+use crate::notation::{
+    key_tonic, spell, tuplet_runs, Bar, Cell, ChordKind, NotatedPart, NoteValue, Score,
+    BARS_PER_LINE, DIVISIONS,
+};
+
+/// Writes the melodic parts of `composition` as a LilyPond score, a staff per part, with the chord
+/// progression as chord names above them.
+pub fn export(composition: &redact_composer::Composition) -> String {
+    let score = Score::of(composition);
+
+    let mut ly = String::from("\\version \"2.24.0\"\n\n\\score {\n  <<\n");
+    ly.push_str(&chord_names(&score));
+    let melodic = score.parts.iter().filter(|part| !part.is_percussion());
+    for (idx, part) in melodic.enumerate() {
+        // Markings shared by all parts are only written once, on the first staff
+        ly.push_str(&staff(&score, part, idx == 0));
+    }
+    ly.push_str("  >>\n  \\layout { }\n}\n");
+
+    ly
+}
+
+fn chord_names(score: &Score) -> String {
+    let bars = score
+        .bars
+        .iter()
+        .zip(score.cells(&score.chord_events()))
+        .map(|(bar, cells)| {
+            let names = cells
+                .iter()
+                .map(|cell| {
+                    let symbol = score
+                        .chords
+                        .iter()
+                        .find(|(start, _)| *start == cell.start)
+                        .filter(|_| !cell.pitches.is_empty() && !cell.tied_from_previous);
+
+                    match symbol {
+                        Some((_, symbol)) => format!(
+                            "{}{}{}",
+                            note_name(symbol.root, bar.fifths),
+                            duration(cell.value, true),
+                            modifier(symbol.kind)
+                        ),
+                        None => format!("s{}", duration(cell.value, true)),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            format!("{} |", names.join(" "))
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "    \\new ChordNames \\chordmode {{\n{}    }}\n",
+        lines(bars)
+    )
+}
+
+fn staff(score: &Score, part: &NotatedPart, marked: bool) -> String {
+    let mut bars = vec![];
+    let mut previous: Option<&Bar> = None;
+    for (bar, cells) in score.bars.iter().zip(score.cells(&part.events)) {
+        let mut ly = String::new();
+        let changes = bar.changes(previous);
+        if changes.key {
+            let (step, alter) = key_tonic(bar.fifths);
+            ly.push_str(&format!(
+                "\\key {}{} \\major ",
+                step.to_ascii_lowercase(),
+                suffix(alter)
+            ));
+        }
+        if changes.time {
+            ly.push_str(&format!("\\time {}/{} ", bar.beats_per_bar, bar.beat_type));
+        }
+        if marked {
+            if let Some(mark) = &bar.rehearsal {
+                ly.push_str(&format!("\\mark \"{}\" ", mark));
+            }
+            if changes.tempo {
+                ly.push_str(&format!(
+                    "\\tempo {} = {} ",
+                    bar.beat_type,
+                    bar.beat_bpm().round()
+                ));
+            }
+        }
+
+        if cells.iter().all(|cell| cell.pitches.is_empty()) {
+            ly.push_str(&format!(
+                "R1*{} ",
+                whole_notes(bar.timing.end - bar.timing.start)
+            ));
+        } else {
+            for run in tuplet_runs(&cells) {
+                let notes = run
+                    .iter()
+                    .map(|cell| note(cell, bar.fifths))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                if run[0].value.triplet {
+                    ly.push_str(&format!("\\tuplet 3/2 {{ {} }} ", notes));
+                } else {
+                    ly.push_str(&notes);
+                    ly.push(' ');
+                }
+            }
+        }
+
+        ly.push('|');
+        bars.push(ly);
+        previous = Some(bar);
+    }
+
+    format!(
+        "    \\new Staff \\with {{ instrumentName = \"{}\" }} {{\n      \\clef {}\n{}    }}\n",
+        part.name(),
+        part.clef().name(),
+        lines(bars)
+    )
+}
+
+/// A note, chord or rest as written within a tuplet (if a triplet), tied to the next if needed.
+fn note(cell: &Cell, fifths: i32) -> String {
+    let pitches = cell
+        .pitches
+        .iter()
+        .map(|pitch| pitch_name(*pitch, fifths))
+        .collect::<Vec<_>>();
+    let tie = if cell.tied_to_next { "~" } else { "" };
+
+    match pitches.len() {
+        0 => format!("r{}", duration(cell.value, false)),
+        1 => format!("{}{}{}", pitches[0], duration(cell.value, false), tie),
+        _ => format!(
+            "<{}>{}{}",
+            pitches.join(" "),
+            duration(cell.value, false),
+            tie
+        ),
+    }
+}
+
+/// The note's duration, scaled by `2/3` for triplets outside of a tuplet.
+fn duration(value: NoteValue, scaled: bool) -> String {
+    let dot = if value.dotted { "." } else { "" };
+    let scale = if scaled && value.triplet { "*2/3" } else { "" };
+
+    format!("{}{}{}", value.denominator, dot, scale)
+}
+
+/// Absolute pitch, with `c'` being middle C.
+fn pitch_name(pitch: u8, fifths: i32) -> String {
+    let octave = pitch as i32 / 12 - 1;
+    let marks = if octave >= 3 {
+        "'".repeat((octave - 3) as usize)
+    } else {
+        ",".repeat((3 - octave) as usize)
+    };
+
+    format!("{}{}", note_name(pitch, fifths), marks)
+}
+
+fn note_name(pitch: u8, fifths: i32) -> String {
+    let (step, alter) = spell(pitch, fifths);
+
+    format!("{}{}", step.to_ascii_lowercase(), suffix(alter))
+}
+
+fn suffix(alter: i32) -> &'static str {
+    match alter {
+        1 => "is",
+        -1 => "es",
+        _ => "",
+    }
+}
+
+/// The chord mode modifier, which matches the chord's suffix apart from a flat fifth.
+fn modifier(kind: ChordKind) -> String {
+    match kind {
+        ChordKind::Major => String::new(),
+        ChordKind::HalfDiminished => String::from(":m7.5-"),
+        kind => format!(":{}", kind.suffix()),
+    }
+}
+
+/// `len` (in divisions) as a fraction of a whole note.
+fn whole_notes(len: i32) -> String {
+    let whole = 4 * DIVISIONS;
+    let divisor = gcd(len, whole).max(1);
+
+    format!("{}/{}", len / divisor, whole / divisor)
+}
+
+fn gcd(a: i32, b: i32) -> i32 {
+    if b == 0 {
+        a.abs()
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn lines(bars: Vec<String>) -> String {
+    bars.chunks(BARS_PER_LINE)
+        .map(|line| format!("      {}\n", line.join(" ")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::fixtures::*;
+
+    #[test]
+    fn durations_scale_triplets_outside_tuplets() {
+        assert_eq!(duration(QUARTER, false), "4");
+        assert_eq!(duration(NoteValue::new(8, true, false), false), "8.");
+        assert_eq!(duration(EIGHTH_TRIPLET, false), "8");
+        assert_eq!(duration(EIGHTH_TRIPLET, true), "8*2/3");
+        assert_eq!(duration(NoteValue::new(2, false, false), true), "2");
+    }
+
+    #[test]
+    fn pitches_are_absolute_from_middle_c() {
+        assert_eq!(pitch_name(60, 0), "c'");
+        assert_eq!(pitch_name(72, 0), "c''");
+        assert_eq!(pitch_name(48, 0), "c");
+        assert_eq!(pitch_name(36, 0), "c,");
+        assert_eq!(pitch_name(59, 0), "b");
+    }
+
+    #[test]
+    fn pitches_are_spelled_for_key() {
+        assert_eq!(pitch_name(61, 0), "cis'");
+        assert_eq!(pitch_name(61, -1), "des'");
+        assert_eq!(pitch_name(70, -2), "bes'");
+    }
+
+    #[test]
+    fn chords_rests_and_ties() {
+        let tied = Cell {
+            tied_to_next: true,
+            ..cell(0, QUARTER, &[60])
+        };
+
+        assert_eq!(note(&tied, 0), "c'4~");
+        assert_eq!(note(&cell(0, QUARTER, &[60, 64]), 0), "<c' e'>4");
+        assert_eq!(note(&cell(0, QUARTER, &[]), 0), "r4");
+    }
+
+    #[test]
+    fn chord_modifiers() {
+        assert_eq!(modifier(ChordKind::Major), "");
+        assert_eq!(modifier(ChordKind::MinorSeventh), ":m7");
+        assert_eq!(modifier(ChordKind::HalfDiminished), ":m7.5-");
+    }
+}
@@ -1,17 +1,31 @@
+mod abc;
+mod automation;
+mod bass;
 mod chord_progression;
+mod drums;
+mod dynamics;
+mod feel;
+mod lilypond;
 mod melody;
+mod midi;
+mod mixing;
+mod musicxml;
+mod notation;
 mod orchestration;
 mod parts;
+mod stems;
 mod structure;
+mod style;
 mod util;
 
 use serde::{Deserialize, Serialize};
 use std::{fs, vec};
 
-use crate::orchestration::{Instrumentation, RandomInstrumentation};
+use crate::feel::RandomSwing;
+use crate::orchestration::Instrumentation;
 use crate::structure::Sections;
+use crate::style::Style;
 use crate::util::{RandomKey, RandomTempo, RandomTimeSignature};
-use redact_composer::midi::convert::MidiConverter;
 use redact_composer::render::{AdhocRenderer, RenderEngine};
 use redact_composer::synthesis::{SF2Synthesizable, SF2Synthesizer};
 use redact_composer::util::IntoSegment;
@@ -20,13 +34,22 @@ use redact_composer::{Composer, Element, Renderer};
 fn main() {
     env_logger::init();
 
+    // An optional style preset name, or path to a custom style in JSON. Fields left out of the
+    // file default to the unconstrained "free" style.
+    let style = std::env::args().nth(1).map(|arg| {
+        Style::preset(&arg).unwrap_or_else(|| {
+            Style::from_file(&arg)
+                .unwrap_or_else(|err| panic!("Error loading style ({:?}): {}", arg, err))
+        })
+    });
+
     let composer = Composer::from(Renderers::standard());
 
     let composition_length = composer.options.ticks_per_beat * 6 * 8 * 8;
-    let composition = composer.compose(Composition.over(0..composition_length));
+    let composition = composer.compose(Composition { style }.over(0..composition_length));
 
     let output_dir = "./composition-outputs";
-    let midi = MidiConverter::convert(&composition);
+    let midi = midi::convert(&composition);
     fs::create_dir_all(output_dir)
         .and_then(|_| midi.save(format!("{}/output.mid", output_dir)))
         .expect("Error saving midi");
@@ -34,6 +57,19 @@ fn main() {
     let json = serde_json::to_string_pretty(&composition).expect("Error serializing");
     fs::write(format!("{}/output.json", output_dir), json).expect("Error saving json");
 
+    let score = musicxml::export(&composition);
+    fs::write(format!("{}/output.musicxml", output_dir), score).expect("Error saving musicxml");
+    fs::write(
+        format!("{}/output.ly", output_dir),
+        lilypond::export(&composition),
+    )
+    .expect("Error saving lilypond");
+    fs::write(
+        format!("{}/output.abc", output_dir),
+        abc::export(&composition),
+    )
+    .expect("Error saving abc");
+
     let sound_font_path = "./sounds/sound_font.sf2";
     let synth = SF2Synthesizer::new(sound_font_path).unwrap_or_else(|_| {
         panic!(
@@ -44,10 +80,24 @@ fn main() {
     midi.synthesize_with(&synth)
         .to_file(format!("{}/output.wav", output_dir))
         .expect("Error during synthesis");
+
+    // One midi and wav file per part role and instrument, for mixing separately
+    let stems_dir = format!("{}/stems", output_dir);
+    fs::create_dir_all(&stems_dir).expect("Error creating stems directory");
+    for (name, stem) in stems::split(&composition) {
+        stem.save(format!("{}/{}.mid", stems_dir, name))
+            .expect("Error saving stem midi");
+        stem.synthesize_with(&synth)
+            .to_file(format!("{}/{}.wav", stems_dir, name))
+            .expect("Error during stem synthesis");
+    }
 }
 
-#[derive(Element, Serialize, Deserialize, Copy, Clone, Debug)]
-pub struct Composition;
+#[derive(Element, Serialize, Deserialize, Clone, Debug)]
+pub struct Composition {
+    /// Chosen at random if not provided.
+    pub style: Option<Style>,
+}
 
 struct Renderers;
 
@@ -57,18 +107,27 @@ impl Renderers {
             + Self::composition_renderer()
             + structure::renderers()
             + chord_progression::renderers()
+            + feel::renderers()
             + orchestration::renderers()
             + parts::renderers()
             + util::renderers()
     }
 
     fn composition_renderer() -> impl Renderer<Element = Composition> {
-        AdhocRenderer::<Composition>::new(|composition, _| {
+        AdhocRenderer::<Composition>::new(|composition, ctx| {
+            let style = composition
+                .element
+                .style
+                .clone()
+                .unwrap_or_else(|| Style::random(&mut ctx.rng_with_seed("style")));
+
             Ok(vec![
+                style.clone().over(composition),
                 RandomKey.over(composition),
                 RandomTimeSignature.over(composition),
                 RandomTempo.over(composition),
-                RandomInstrumentation.over(composition),
+                RandomSwing.over(composition),
+                style.instrumentation.clone().over(composition),
                 Sections.over(composition),
             ])
         })
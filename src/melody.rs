@@ -1,17 +1,25 @@
+use crate::automation::{Automation, AutomationLane, AutomationShape};
+use crate::dynamics::VelocityShaper;
+use crate::feel::{Humanize, Polyrhythm, Swing};
 use crate::melody::MelodyDirectiveOutput::{NoteChoice, NoteMask};
+use crate::mixing::{Mix, PartRole};
+use crate::orchestration::{InstrumentFamily, InstrumentRange};
 use crate::structure::PhraseDivider;
 use rand::distributions::WeightedIndex;
 use rand::prelude::SliceRandom;
 use rand::Rng;
+use redact_composer::midi::gm::elements::Instrument;
 use redact_composer::musical::elements::{Key, TimeSignature};
 use redact_composer::musical::rhythm::{Rhythm, Subdivision};
-use redact_composer::musical::{Interval, Note, NoteIterator};
+use redact_composer::musical::{Interval, Note, NoteIterator, PitchClassCollection};
 use redact_composer::render::context::TimingRelation::{During, Overlapping};
 use redact_composer::render::{AdhocRenderer, RenderEngine};
+use redact_composer::timing::elements::Tempo;
 use redact_composer::timing::Timing;
 use redact_composer::util::{HashMap, IntoSegment};
 use redact_composer::{Element, Renderer};
 use serde::{Deserialize, Serialize};
+use std::iter::once;
 use std::ops::{AddAssign, MulAssign, Range};
 
 pub fn renderers() -> RenderEngine {
@@ -104,7 +112,6 @@ enum MelodyDirectiveOutput {
     /// be between 0.0 and 1.0 (Effectively it multiplies to the note choice probabilities.)
     /// When two [`NoteMask`]s are applied simultaneously, they are also
     /// applied multiplicatively.
-    #[allow(dead_code)]
     NoteMask(HashMap<Note, f32>),
 }
 
@@ -131,6 +138,444 @@ impl MelodyDirectiveOutput {
     }
 }
 
+/// Controls how a [`MelodyLine`] closes each [`PhraseDivider`]: the final note of a phrase prefers
+/// a stable degree (1, 3 or 5) and is held longer, optionally followed by a rest.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct PhraseEndings {
+    /// Probability of resting before the next phrase at 120 bpm.
+    pub rest_probability: f32,
+}
+
+impl Default for PhraseEndings {
+    fn default() -> Self {
+        Self {
+            rest_probability: 0.4,
+        }
+    }
+}
+
+impl PhraseEndings {
+    pub fn random(rng: &mut impl Rng) -> PhraseEndings {
+        PhraseEndings {
+            rest_probability: rng.gen_range(0.2..0.6),
+        }
+    }
+
+    fn rest_probability_at(&self, tempo: Option<&Tempo>) -> f64 {
+        let tempo_scale = tempo.map(|t| t.bpm() as f32 / 120.0).unwrap_or(1.0);
+
+        (self.rest_probability * tempo_scale).clamp(0.0, 1.0) as f64
+    }
+
+    /// Produces a rhythm for a phrase of the given length, returning it along with the start
+    /// (relative to the phrase) of its final held note.
+    fn phrase_rhythm(
+        &self,
+        phrase_len: i32,
+        ts: &TimeSignature,
+        allowed_divisions: &[(Vec<i32>, i32)],
+        rest_probability: f64,
+        cell: Option<i32>,
+        rng: &mut impl Rng,
+    ) -> (Rhythm, i32) {
+        let rest_len = [ts.half_beat(), ts.beat()]
+            .into_iter()
+            .filter(|rest| *rest <= phrase_len / 4)
+            .filter(|_| rng.gen_bool(rest_probability))
+            .last()
+            .unwrap_or(0);
+        let hold_len = [ts.beat(), ts.beat() + ts.half_beat(), ts.beats(2)]
+            .into_iter()
+            .filter(|hold| hold + rest_len <= phrase_len / 2)
+            .collect::<Vec<_>>()
+            .choose(rng)
+            .copied();
+
+        let body_len = phrase_len - rest_len - hold_len.unwrap_or(0);
+        let mut rhythm = match cell.filter(|cell| *cell > 0 && *cell <= body_len) {
+            Some(cell_len) => {
+                let cell =
+                    Rhythm::random_with_subdivisions_weights(cell_len, allowed_divisions, rng);
+                let repeats = body_len / cell_len;
+                let remainder = body_len - repeats * cell_len;
+                let cells = (0..repeats).fold(Rhythm::new(), |acc, _| acc + cell.clone());
+
+                if remainder > 0 {
+                    cells
+                        + Rhythm::random_with_subdivisions_weights(
+                            remainder,
+                            allowed_divisions,
+                            rng,
+                        )
+                } else {
+                    cells
+                }
+            }
+            None => Rhythm::random_with_subdivisions_weights(body_len, allowed_divisions, rng),
+        };
+        let ending_start = if let Some(hold_len) = hold_len {
+            rhythm = rhythm + Rhythm::from([hold_len]);
+
+            body_len
+        } else {
+            rhythm.0.last().map(|sub| sub.start).unwrap_or(0)
+        };
+
+        if rest_len > 0 {
+            let mut rest = Rhythm::from([rest_len]);
+            rest.0.iter_mut().for_each(|sub| sub.is_rest = true);
+            rhythm = rhythm + rest;
+        }
+
+        (rhythm, ending_start)
+    }
+
+    /// Masks out unstable degrees of `key` so phrase-ending notes resolve.
+    fn stable_note_mask(key: &Key, choices: &HashMap<Note, f32>) -> MelodyDirectiveOutput {
+        let degrees = key.pitch_classes();
+        let stable = [0, 2, 4]
+            .into_iter()
+            .flat_map(|idx| degrees.get(idx))
+            .collect::<Vec<_>>();
+
+        NoteMask(
+            choices
+                .keys()
+                .map(|note| {
+                    if stable.contains(&&note.pitch_class()) {
+                        (*note, 1.0)
+                    } else {
+                        (*note, 0.2)
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// How a note is played relative to its rhythmic subdivision.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Articulation {
+    /// Shortened to half of its duration.
+    Staccato,
+    /// Slightly overlapping the following note, unless it ends its phrase.
+    Legato,
+    /// Held for its full duration.
+    Tenuto,
+    /// Full duration, with a velocity boost.
+    Accent,
+}
+
+impl Articulation {
+    /// Weights of each phrase articulation, favoring detached playing at fast tempos or for
+    /// plucked/struck instruments and connected playing at slow tempos or for sustaining ones.
+    fn weights(
+        tempo: Option<&Tempo>,
+        family: Option<InstrumentFamily>,
+    ) -> Vec<(Articulation, f32)> {
+        let bpm = tempo.map(|t| t.bpm() as f32).unwrap_or(120.0);
+        let tempo_factor = (bpm / 120.0).clamp(0.5, 2.0);
+        let (staccato, legato, tenuto) = match family {
+            Some(
+                InstrumentFamily::Piano
+                | InstrumentFamily::ChromaticPercussion
+                | InstrumentFamily::Guitar
+                | InstrumentFamily::Ethnic
+                | InstrumentFamily::Percussive,
+            ) => (3.0, 0.5, 3.0),
+            Some(InstrumentFamily::Bass) => (2.0, 1.0, 5.0),
+            Some(
+                InstrumentFamily::Strings
+                | InstrumentFamily::Ensemble
+                | InstrumentFamily::Brass
+                | InstrumentFamily::Reed
+                | InstrumentFamily::Pipe
+                | InstrumentFamily::Organ
+                | InstrumentFamily::SynthPad,
+            ) => (1.5, 4.0, 3.0),
+            _ => (2.0, 2.0, 4.0),
+        };
+
+        vec![
+            (Articulation::Staccato, staccato * tempo_factor),
+            (Articulation::Legato, legato / tempo_factor),
+            (Articulation::Tenuto, tenuto),
+            (Articulation::Accent, 0.5),
+        ]
+    }
+
+    /// Adjusts a note's timing and velocity.
+    fn apply(
+        &self,
+        timing: Range<i32>,
+        velocity: u8,
+        phrase_end: i32,
+        repeated: bool,
+        ts: &TimeSignature,
+    ) -> (Range<i32>, u8) {
+        match self {
+            Articulation::Staccato => {
+                let len = ((timing.end - timing.start) / 2).max(1);
+
+                (timing.start..(timing.start + len), velocity)
+            }
+            Articulation::Legato => {
+                let overlap = ts.quarter_beat() / 4;
+                let end = if !repeated && timing.end + overlap <= phrase_end {
+                    timing.end + overlap
+                } else {
+                    timing.end
+                };
+
+                (timing.start..end, velocity)
+            }
+            Articulation::Tenuto => (timing, velocity),
+            Articulation::Accent => (timing, velocity.saturating_add(15).min(127)),
+        }
+    }
+}
+
+/// Articulates a line of `notes`, each with its subdivision and velocity, choosing an
+/// [`Articulation`] per phrase and accenting some of the notes landing on a beat.
+pub fn articulate(
+    notes: &[(Note, Range<i32>, u8)],
+    phrases: Vec<Range<i32>>,
+    line: Range<i32>,
+    tempo: Option<&Tempo>,
+    family: Option<InstrumentFamily>,
+    ts: &TimeSignature,
+    rng: &mut impl Rng,
+) -> Vec<(Range<i32>, Range<i32>, u8)> {
+    let weights = Articulation::weights(tempo, family);
+    let phrase_articulations = phrases
+        .into_iter()
+        .map(|phrase| {
+            let articulation = weights
+                .choose_weighted(rng, |(_, w)| *w)
+                .map(|(a, _)| *a)
+                .unwrap_or(Articulation::Tenuto);
+
+            (phrase, articulation)
+        })
+        .collect::<Vec<_>>();
+
+    notes
+        .iter()
+        .enumerate()
+        .map(|(idx, (note, div, velocity))| {
+            // Notes repeated without a gap are never overlapped, which would cut them short
+            let repeated = notes
+                .get(idx + 1)
+                .map(|(next, next_div, _)| next == note && next_div.start == div.end)
+                .unwrap_or(false);
+            let (phrase, articulation) = phrase_articulations
+                .iter()
+                .find(|(phrase, _)| phrase.contains(&div.start))
+                .cloned()
+                .unwrap_or((line.clone(), Articulation::Tenuto));
+            // Accent some of the notes landing on a beat, regardless of phrase articulation
+            let articulation = if (div.start - phrase.start) % ts.beat() == 0 && rng.gen_bool(0.15)
+            {
+                Articulation::Accent
+            } else {
+                articulation
+            };
+            let (timing, velocity) =
+                articulation.apply(div.clone(), *velocity, phrase.end, repeated, ts);
+
+            (phrase, timing, velocity)
+        })
+        .collect()
+}
+
+/// A note produced by a [`MelodyLine`], prior to being output as a segment.
+#[derive(Debug, Clone)]
+struct LineNote {
+    note: Note,
+    timing: Range<i32>,
+    velocity: u8,
+    /// Timing of the [`PhraseDivider`] containing this note.
+    phrase: Range<i32>,
+    /// Whether this note was targeted by a [`MelodyDirective::KeyNote`].
+    key_note: bool,
+}
+
+/// Decorates notes of a [`MelodyLine`] within the same [`Melody`] with ornaments built from the
+/// current [`Key`]'s scale.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Ornamentation {
+    /// Probability of ornamenting a long key note. Other notes are ornamented less often.
+    pub density: f32,
+}
+
+impl Default for Ornamentation {
+    fn default() -> Self {
+        Self { density: 0.3 }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Ornament {
+    /// A short neighbor note just before the main note.
+    Grace,
+    UpperMordent,
+    LowerMordent,
+    /// A turn around the main note, delayed to the end of the main note.
+    Turn,
+    /// Alternates with the upper neighbor through most of the main note.
+    Trill,
+}
+
+impl Ornamentation {
+    fn decorate(
+        &self,
+        notes: Vec<LineNote>,
+        key: &Key,
+        ts: &TimeSignature,
+        rng: &mut impl Rng,
+    ) -> Vec<LineNote> {
+        let unit = (ts.quarter_beat() / 2).max(1);
+        let neighbors = |note: Note| {
+            let upper = key
+                .notes_in_range((note + Interval(1))..(note + Interval(3)))
+                .first()
+                .copied();
+            let lower = key
+                .notes_in_range(Note(note.0.saturating_sub(2))..note)
+                .last()
+                .copied();
+
+            (upper, lower)
+        };
+
+        let mut decorated: Vec<LineNote> = vec![];
+        for line_note in notes {
+            let len = line_note.timing.end - line_note.timing.start;
+            let probability = self.density
+                * if line_note.key_note { 1.0 } else { 0.3 }
+                * (len as f32 / ts.beat() as f32).min(1.0);
+            let choices = if len >= ts.beats(2) {
+                vec![Ornament::Trill, Ornament::Turn, Ornament::UpperMordent]
+            } else if len >= ts.beat() {
+                vec![
+                    Ornament::Turn,
+                    Ornament::UpperMordent,
+                    Ornament::LowerMordent,
+                    Ornament::Grace,
+                ]
+            } else if len >= unit * 3 {
+                vec![
+                    Ornament::UpperMordent,
+                    Ornament::LowerMordent,
+                    Ornament::Grace,
+                ]
+            } else {
+                vec![]
+            };
+
+            let ornament = choices
+                .choose(rng)
+                .copied()
+                .filter(|_| rng.gen_bool(probability.clamp(0.0, 1.0) as f64));
+            let (upper, lower) = neighbors(line_note.note);
+            let (start, end) = (line_note.timing.start, line_note.timing.end);
+            let ornament_velocity = line_note.velocity.saturating_sub(15);
+            let ornament_note = |note: Note, timing: Range<i32>, velocity: u8| LineNote {
+                note,
+                timing,
+                velocity,
+                phrase: line_note.phrase.clone(),
+                key_note: false,
+            };
+
+            match (ornament, upper, lower) {
+                (Some(Ornament::Grace), Some(upper), Some(lower))
+                    if start - unit >= line_note.phrase.start =>
+                {
+                    // Steal time from the preceding note rather than moving the main note
+                    if let Some(prev) = decorated.last_mut() {
+                        if prev.timing.end > start - unit {
+                            if prev.timing.start >= start - unit {
+                                decorated.push(line_note);
+                                continue;
+                            }
+                            prev.timing.end = start - unit;
+                        }
+                    }
+                    let grace = *[upper, lower].choose(rng).unwrap();
+                    decorated.push(ornament_note(
+                        grace,
+                        (start - unit)..start,
+                        ornament_velocity,
+                    ));
+                    decorated.push(line_note);
+                }
+                (Some(Ornament::UpperMordent), Some(neighbor), _)
+                | (Some(Ornament::LowerMordent), _, Some(neighbor)) => {
+                    decorated.push(ornament_note(
+                        line_note.note,
+                        start..(start + unit),
+                        line_note.velocity,
+                    ));
+                    decorated.push(ornament_note(
+                        neighbor,
+                        (start + unit)..(start + unit * 2),
+                        ornament_velocity,
+                    ));
+                    decorated.push(LineNote {
+                        timing: (start + unit * 2)..end,
+                        ..line_note
+                    });
+                }
+                (Some(Ornament::Turn), Some(upper), Some(lower)) => {
+                    let turn_start = end - unit * 4;
+                    decorated.push(LineNote {
+                        timing: start..turn_start,
+                        ..line_note.clone()
+                    });
+                    [upper, line_note.note, lower, line_note.note]
+                        .into_iter()
+                        .enumerate()
+                        .for_each(|(idx, note)| {
+                            let note_start = turn_start + unit * idx as i32;
+                            decorated.push(ornament_note(
+                                note,
+                                note_start..(note_start + unit),
+                                ornament_velocity,
+                            ))
+                        });
+                }
+                (Some(Ornament::Trill), Some(upper), _) => {
+                    let trill_end = start + (len * 3 / 4) / (unit * 2) * (unit * 2);
+                    decorated.push(ornament_note(
+                        line_note.note,
+                        start..(start + unit),
+                        line_note.velocity,
+                    ));
+                    (start + unit..trill_end)
+                        .step_by(unit as usize)
+                        .enumerate()
+                        .for_each(|(idx, note_start)| {
+                            let note = if idx % 2 == 0 { upper } else { line_note.note };
+                            decorated.push(ornament_note(
+                                note,
+                                note_start..(note_start + unit),
+                                ornament_velocity,
+                            ))
+                        });
+                    decorated.push(LineNote {
+                        timing: trill_end..end,
+                        ..line_note
+                    });
+                }
+                _ => decorated.push(line_note),
+            }
+        }
+
+        decorated
+    }
+}
+
 #[derive(Element, Serialize, Deserialize, Debug)]
 struct MelodyLine;
 
@@ -168,6 +613,13 @@ impl MelodyLine {
                         .element
                 }
             };
+            let instrument = ctx
+                .find::<Instrument>()
+                .within_ancestor::<Melody>()
+                .with_timing(During, melody_line)
+                .get()
+                .map(|instrument| *instrument.element);
+            let instrument_range = instrument.map(InstrumentRange::of);
             let dividers = ctx
                 .find::<PhraseDivider>()
                 .with_timing(Overlapping, melody_line)
@@ -177,6 +629,24 @@ impl MelodyLine {
                 .within_ancestor::<Melody>()
                 .with_timing(Overlapping, melody_line)
                 .require_all()?;
+            let phrase_endings = ctx
+                .find::<PhraseEndings>()
+                .with_timing(During, melody_line)
+                .get()
+                .map(|endings| *endings.element)
+                .unwrap_or_default();
+            let tempo = ctx
+                .find::<Tempo>()
+                .with_timing(During, melody_line)
+                .get()
+                .map(|tempo| tempo.element);
+            let rest_probability = phrase_endings.rest_probability_at(tempo);
+            let cell = ctx
+                .find::<Polyrhythm>()
+                .with_timing(During, melody_line)
+                .get()
+                .filter(|polyrhythm| polyrhythm.element.melody)
+                .map(|polyrhythm| polyrhythm.element.pulse_len(ts));
 
             let mut divisions = [
                 vec![ts.half_beat()],
@@ -199,14 +669,27 @@ impl MelodyLine {
                 .collect::<Vec<_>>();
 
             let mut rhythm_rng = ctx.rng_with_seed(rng.gen::<u64>());
+            let mut phrase_ending_starts = vec![];
+            let mut phrases = vec![];
             let rhythm = if let Some(dividers) = dividers {
-                dividers.into_iter().fold(Rhythm::new(), |acc, div| {
-                    acc + Rhythm::random_with_subdivisions_weights(
-                        div.timing.len(),
-                        &allowed_divisions,
-                        &mut rhythm_rng,
-                    )
-                })
+                dividers
+                    .into_iter()
+                    .fold((Rhythm::new(), 0), |(acc, offset), div| {
+                        let (phrase, ending_start) = phrase_endings.phrase_rhythm(
+                            div.timing.len(),
+                            ts,
+                            &allowed_divisions,
+                            rest_probability,
+                            cell,
+                            &mut rhythm_rng,
+                        );
+                        phrase_ending_starts.push(melody_line.timing.start + offset + ending_start);
+                        let phrase_start = melody_line.timing.start + offset;
+                        phrases.push(phrase_start..(phrase_start + div.timing.len()));
+
+                        (acc + phrase, offset + div.timing.len())
+                    })
+                    .0
             } else {
                 Rhythm::random_with_subdivisions_weights(
                     melody_line.timing.len(),
@@ -220,6 +703,10 @@ impl MelodyLine {
             let mut notes = rhythm
                 .iter_over(melody_line)
                 .scan(None, |prev_note, t| {
+                    if t.is_rest {
+                        return Some((None, t));
+                    }
+
                     let mut directives = directives
                         .iter()
                         .filter(|directive| {
@@ -250,6 +737,11 @@ impl MelodyLine {
 
                             acc
                         });
+                    let mut note_choices = note_choices;
+                    if phrase_ending_starts.contains(&t.start) {
+                        PhraseEndings::stable_note_mask(key, &note_choices)
+                            .merge_into(&mut note_choices);
+                    }
 
                     if let Ok(dist) = WeightedIndex::new(note_choices.values()) {
                         let chosen_note =
@@ -263,12 +755,112 @@ impl MelodyLine {
                 })
                 .collect::<Vec<_>>();
 
+            // The line is moved by octaves into the instrument's range as a whole, so runs keep
+            // their contour
+            if let Some(range) = &instrument_range {
+                range.fit_notes(notes.iter_mut().flat_map(|(note, _)| note.as_mut()));
+            }
+
             Self::merge_ranges(&mut notes, &mut rng);
 
-            let play_notes = notes
+            let velocities = VelocityShaper::from_context(
+                ctx,
+                melody_line.timing.start..melody_line.timing.end,
+            )?;
+            let pitched_notes = notes
                 .into_iter()
                 .flat_map(|(opt_note, div)| opt_note.map(|note| (note, div)))
-                .map(|(note, div)| note.play(rng.gen_range(80..=110)).over(div))
+                .map(|(note, div)| {
+                    let velocity = velocities.velocity(div.start, &mut rng);
+
+                    (note, div.start..div.end, velocity)
+                })
+                .collect::<Vec<_>>();
+            let articulated = articulate(
+                &pitched_notes,
+                phrases,
+                melody_line.timing.start..melody_line.timing.end,
+                tempo,
+                instrument.map(InstrumentFamily::of),
+                ts,
+                &mut ctx.rng_with_seed("articulation"),
+            );
+            let line_notes = pitched_notes
+                .into_iter()
+                .zip(articulated)
+                .map(|((note, div, _), (phrase, timing, velocity))| {
+                    let key_note = directives.iter().any(|dir| {
+                        matches!(dir.element, MelodyDirective::KeyNote(_))
+                            && dir
+                                .timing
+                                .start_shifted_by(-lead_amount)
+                                .contains(&div.start)
+                    });
+
+                    LineNote {
+                        note,
+                        timing,
+                        velocity,
+                        phrase,
+                        key_note,
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let line_notes = if let Some(ornamentation) = ctx
+                .find::<Ornamentation>()
+                .within_ancestor::<Melody>()
+                .with_timing(During, melody_line)
+                .get()
+            {
+                let mut ornament_rng = ctx.rng_with_seed("ornaments");
+                ornamentation
+                    .element
+                    .decorate(line_notes, key, ts, &mut ornament_rng)
+            } else {
+                line_notes
+            };
+
+            let line_timing = melody_line.timing.start..melody_line.timing.end;
+            let swing = Swing::grid(ctx, line_timing.clone())?;
+            let humanizer =
+                Humanize::of_part(ctx, line_timing.clone())?.humanizer(ctx, line_timing)?;
+            let mut humanize_rng = ctx.rng_with_seed("humanize");
+            // Lead synths sometimes slide up into key notes
+            let lead = ctx
+                .find::<Mix>()
+                .within_ancestor::<Melody>()
+                .with_timing(During, melody_line)
+                .get()
+                .is_some_and(|mix| mix.element.role == PartRole::Lead);
+            let slides = lead
+                && instrument
+                    .map(|i| InstrumentFamily::of(i) == InstrumentFamily::SynthLead)
+                    .unwrap_or(false);
+            let mut slide_rng = ctx.rng_with_seed("slides");
+            let play_notes = line_notes
+                .into_iter()
+                .flat_map(|n| {
+                    let (timing, velocity) = humanizer.apply(
+                        swing.apply_range(n.timing),
+                        n.velocity,
+                        None,
+                        &mut humanize_rng,
+                    );
+                    let slide = (slides && n.key_note && slide_rng.gen_bool(0.5)).then(|| {
+                        let slide_len = ((timing.end - timing.start) / 3).min(ts.half_beat());
+
+                        Automation {
+                            lane: AutomationLane::PitchBend,
+                            shape: AutomationShape::Ramp,
+                            from: -1.0,
+                            to: 0.0,
+                        }
+                        .over(timing.start..(timing.start + slide_len))
+                    });
+
+                    once(n.note.play(velocity).over(timing)).chain(slide)
+                })
                 .collect::<Vec<_>>();
 
             Ok(play_notes)
@@ -0,0 +1,208 @@
+use crate::automation::{Automation, AutomationLane};
+use crate::mixing::{Mix, PartRole};
+use midly::num::{u28, u4, u7};
+use midly::{Header, MetaMessage, MidiMessage, Smf, Track, TrackEvent, TrackEventKind};
+use redact_composer::elements::Part;
+use redact_composer::midi::convert::MidiConverter;
+use redact_composer::midi::gm::elements::DrumHit;
+use redact_composer::Composition;
+
+const DRUM_CHANNEL: u8 = 9;
+/// Ticks between sampled [`Automation`] messages.
+const AUTOMATION_RESOLUTION: i32 = 20;
+
+/// The track [`MidiConverter`] produced for a [`Part`], carrying its [`Mix`] and automation.
+pub struct PartTrack {
+    /// The role of the part, if it has a [`Mix`].
+    pub role: Option<PartRole>,
+    pub program: Option<u8>,
+    pub channel: u8,
+    pub track: Track<'static>,
+}
+
+impl PartTrack {
+    pub fn is_percussion(&self) -> bool {
+        self.channel == DRUM_CHANNEL
+    }
+}
+
+/// The tracks of a converted composition: those shared by every part, such as tempo changes,
+/// followed by a track per [`Part`].
+pub struct Tracks {
+    pub header: Header,
+    pub shared: Vec<Track<'static>>,
+    pub parts: Vec<PartTrack>,
+}
+
+/// Converts `composition` with the [`MidiConverter`], adding each [`Part`]'s [`Mix`] and
+/// automation to its track.
+pub fn convert(composition: &Composition) -> Smf<'static> {
+    let tracks = tracks(composition);
+    let mut smf = Smf::new(tracks.header);
+    smf.tracks = tracks
+        .shared
+        .into_iter()
+        .chain(tracks.parts.into_iter().map(|part| part.track))
+        .collect();
+
+    smf
+}
+
+/// Converts `composition` with the [`MidiConverter`], which writes a track per [`Part`] in
+/// composition order after those shared by every part.
+pub fn tracks(composition: &Composition) -> Tracks {
+    let smf = MidiConverter::convert(composition);
+    let parts = composition
+        .tree
+        .iter()
+        .filter(|node| node.value.segment.element_as::<Part>().is_some())
+        .map(|node| node.idx)
+        .collect::<Vec<_>>();
+    let mut tracks = smf.tracks;
+    let part_tracks = tracks.split_off(tracks.len().saturating_sub(parts.len()));
+
+    Tracks {
+        header: smf.header,
+        shared: tracks,
+        parts: parts
+            .into_iter()
+            .zip(part_tracks)
+            .map(|(idx, track)| part_track(composition, idx, track))
+            .collect(),
+    }
+}
+
+/// Starts the part's track with its [`Mix`] and automation lanes at their base values, as its
+/// channel may have been automated by an earlier part, then adds its [`Automation`].
+fn part_track(composition: &Composition, idx: usize, mut track: Track<'static>) -> PartTrack {
+    let start = composition.tree[idx].value.segment.timing.start;
+    let segments = descendants(composition, idx)
+        .into_iter()
+        .map(|idx| &composition.tree[idx].value.segment)
+        .collect::<Vec<_>>();
+    let mix = segments
+        .iter()
+        .find_map(|segment| segment.element_as::<Mix>());
+    let channel = track.iter().find_map(|event| match event.kind {
+        TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+        _ => None,
+    });
+    let program = track.iter().find_map(|event| match event.kind {
+        TrackEventKind::Midi {
+            message: MidiMessage::ProgramChange { program },
+            ..
+        } => Some(program.as_int()),
+        _ => None,
+    });
+
+    if let Some(channel) = channel {
+        let mut messages = mix
+            .map(|mix| mix.controls())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(controller, value)| {
+                let message = MidiMessage::Controller {
+                    controller: u7::new(controller),
+                    value: u7::new(value),
+                };
+
+                (start, message)
+            })
+            .collect::<Vec<_>>();
+        for lane in [
+            AutomationLane::Expression,
+            AutomationLane::Brightness,
+            AutomationLane::PitchBend,
+        ] {
+            messages.extend(Automation::reset(lane).messages(start..start + 1, 1));
+        }
+        for segment in &segments {
+            if let Some(automation) = segment.element_as::<Automation>() {
+                let timing = segment.timing.start..segment.timing.end;
+                messages.extend(automation.messages(timing, AUTOMATION_RESOLUTION));
+            }
+        }
+
+        let events = messages
+            .into_iter()
+            .map(|(tick, message)| {
+                let kind = TrackEventKind::Midi {
+                    channel: u4::new(channel),
+                    message,
+                };
+
+                (tick, kind)
+            })
+            .collect();
+        track = insert(&track, events);
+    }
+
+    PartTrack {
+        role: mix.map(|mix| mix.role),
+        program,
+        channel: channel.unwrap_or(0),
+        track,
+    }
+}
+
+/// Indices of the nodes under `idx`, stopping at nested parts.
+pub fn descendants(composition: &Composition, idx: usize) -> Vec<usize> {
+    let mut descendants = vec![];
+    let mut pending = composition.tree[idx].children.clone();
+    while let Some(child) = pending.pop() {
+        let segment = &composition.tree[child].value.segment;
+        if segment.element_as::<Part>().is_some() {
+            continue;
+        }
+
+        descendants.push(child);
+        if segment.element_as::<DrumHit>().is_none() {
+            pending.extend(composition.tree[child].children.iter().copied());
+        }
+    }
+    descendants.sort();
+
+    descendants
+}
+
+/// Copies `track` with `events` added at their ticks, ahead of the events already at the same tick.
+fn insert(track: &Track<'static>, events: Vec<(i32, TrackEventKind<'static>)>) -> Track<'static> {
+    let mut tick = 0;
+    let mut timed = track
+        .iter()
+        .map(|event| {
+            tick += event.delta.as_int();
+
+            (tick, 1, event.kind)
+        })
+        .filter(|(_, _, kind)| !matches!(kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)))
+        .chain(
+            events
+                .into_iter()
+                .map(|(tick, kind)| (tick.max(0) as u32, 0, kind)),
+        )
+        .collect::<Vec<_>>();
+    timed.sort_by_key(|(tick, order, _)| (*tick, *order));
+    let end = timed
+        .last()
+        .map(|(last, _, _)| *last)
+        .unwrap_or(0)
+        .max(tick);
+
+    let mut previous = 0;
+    let mut inserted = timed
+        .into_iter()
+        .map(|(tick, _, kind)| {
+            let delta = u28::new(tick - previous);
+            previous = tick;
+
+            TrackEvent { delta, kind }
+        })
+        .collect::<Vec<_>>();
+    inserted.push(TrackEvent {
+        delta: u28::new(end - previous),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    inserted
+}
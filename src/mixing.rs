@@ -0,0 +1,81 @@
+use redact_composer::Element;
+use serde::{Deserialize, Serialize};
+
+const VOLUME: u8 = 7;
+const PAN: u8 = 10;
+const REVERB: u8 = 91;
+const CHORUS: u8 = 93;
+
+/// The role a [`Part`](redact_composer::elements::Part) plays in the ensemble, which stays the
+/// same across sections even as its instrument changes.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PartRole {
+    Lead,
+    /// Follows the lead in harmony, played by the first extra instrument.
+    Harmony,
+    Bass,
+    Drums,
+    /// The extra instrument at this index of the
+    /// [`Instrumentation`](crate::orchestration::Instrumentation).
+    Extra(usize),
+}
+
+impl PartRole {
+    pub fn name(&self) -> String {
+        match self {
+            PartRole::Lead => String::from("lead"),
+            PartRole::Harmony => String::from("harmony"),
+            PartRole::Bass => String::from("bass"),
+            PartRole::Drums => String::from("drums"),
+            PartRole::Extra(idx) => format!("extra{}", idx + 1),
+        }
+    }
+}
+
+/// Channel volume, pan and effect sends for the [`Part`](redact_composer::elements::Part) it is
+/// placed in, written as control changes at the start of the part's track.
+#[derive(Element, Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Mix {
+    pub role: PartRole,
+    pub volume: u8,
+    pub pan: u8,
+    pub reverb: u8,
+    pub chorus: u8,
+}
+
+impl Mix {
+    /// Balances levels by role, keeping the lead, bass and drums centered while spreading extras
+    /// alternately left and right, further out for each pair.
+    pub fn of(role: PartRole) -> Mix {
+        let (volume, pan, reverb, chorus) = match role {
+            PartRole::Drums => (100, 64, 30, 0),
+            PartRole::Bass => (105, 64, 10, 0),
+            PartRole::Lead => (110, 64, 45, 20),
+            PartRole::Harmony => (80, 64 + 24, 55, 30),
+            PartRole::Extra(idx) => {
+                let distance = 24 + 12 * (idx as i32 / 2);
+                let side = if idx % 2 == 0 { -1 } else { 1 };
+
+                (85, (64 + side * distance).clamp(0, 127) as u8, 55, 30)
+            }
+        };
+
+        Mix {
+            role,
+            volume,
+            pan,
+            reverb,
+            chorus,
+        }
+    }
+
+    /// Controller numbers and their values.
+    pub fn controls(&self) -> [(u8, u8); 4] {
+        [
+            (VOLUME, self.volume),
+            (PAN, self.pan),
+            (REVERB, self.reverb),
+            (CHORUS, self.chorus),
+        ]
+    }
+}
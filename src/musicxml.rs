@@ -0,0 +1,421 @@
+use crate::notation::{
+    spell, tuplet_runs, Bar, Cell, ChordKind, Clef, NotatedPart, Score, DIVISIONS,
+};
+use std::ops::Range;
+
+const DRUM_CHANNEL: usize = 10;
+
+/// Writes `composition` as a partwise MusicXML score, with a part per role and instrument and one
+/// for the drums.
+pub fn export(composition: &redact_composer::Composition) -> String {
+    let score = Score::of(composition);
+
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n",
+        "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" ",
+        "\"http://www.musicxml.org/dtds/partwise.dtd\">\n",
+        "<score-partwise version=\"4.0\">\n",
+        "  <part-list>\n",
+    ));
+    for (idx, part) in score.parts.iter().enumerate() {
+        xml.push_str(&score_part(idx, part));
+    }
+    xml.push_str("  </part-list>\n");
+    for (idx, part) in score.parts.iter().enumerate() {
+        xml.push_str(&measures(&score, idx, part));
+    }
+    xml.push_str("</score-partwise>\n");
+
+    xml
+}
+
+fn part_id(idx: usize) -> String {
+    format!("P{}", idx + 1)
+}
+
+/// The part's entry in the part list, along with its MIDI playback instruments.
+fn score_part(idx: usize, part: &NotatedPart) -> String {
+    let id = part_id(idx);
+    let instruments = match part.instrument {
+        Some(instrument) => {
+            let channel = (1..=16)
+                .filter(|c| *c != DRUM_CHANNEL)
+                .cycle()
+                .nth(idx)
+                .unwrap_or(1);
+
+            format!(
+                concat!(
+                    "      <score-instrument id=\"{id}-I1\">",
+                    "<instrument-name>{name}</instrument-name></score-instrument>\n",
+                    "      <midi-instrument id=\"{id}-I1\"><midi-channel>{channel}</midi-channel>",
+                    "<midi-program>{program}</midi-program></midi-instrument>\n",
+                ),
+                id = id,
+                name = part.name(),
+                channel = channel,
+                program = u8::from(instrument) as u32 + 1,
+            )
+        }
+        None => {
+            let drum_keys = drum_keys(part);
+            let names = drum_keys.iter().map(|key| {
+                format!(
+                    concat!(
+                        "      <score-instrument id=\"{id}-I{key}\">",
+                        "<instrument-name>{name}</instrument-name></score-instrument>\n",
+                    ),
+                    id = id,
+                    key = key,
+                    name = percussion(*key).0,
+                )
+            });
+            let sounds = drum_keys.iter().map(|key| {
+                format!(
+                    concat!(
+                        "      <midi-instrument id=\"{id}-I{key}\">",
+                        "<midi-channel>{channel}</midi-channel>",
+                        "<midi-unpitched>{unpitched}</midi-unpitched></midi-instrument>\n",
+                    ),
+                    id = id,
+                    key = key,
+                    channel = DRUM_CHANNEL,
+                    unpitched = *key as u32 + 1,
+                )
+            });
+
+            names.chain(sounds).collect()
+        }
+    };
+
+    format!(
+        "    <score-part id=\"{}\">\n      <part-name>{}</part-name>\n{}    </score-part>\n",
+        id,
+        part.name(),
+        instruments
+    )
+}
+
+fn measures(score: &Score, idx: usize, part: &NotatedPart) -> String {
+    let id = part_id(idx);
+    let clef = part.clef();
+    // Markings shared by all parts are only shown once, above the first
+    let marked = idx == 0;
+
+    let mut xml = format!("  <part id=\"{}\">\n", id);
+    let mut previous: Option<&Bar> = None;
+    for (number, (bar, cells)) in score.bars.iter().zip(score.cells(&part.events)).enumerate() {
+        xml.push_str(&format!("    <measure number=\"{}\">\n", number + 1));
+        xml.push_str(&attributes(bar, previous, clef));
+        if marked {
+            xml.push_str(&directions(bar, previous));
+        }
+
+        if cells.iter().all(|cell| cell.pitches.is_empty()) {
+            if marked {
+                xml.push_str(&harmonies(score, bar.timing.clone(), bar.fifths));
+            }
+            xml.push_str(&format!(
+                concat!(
+                    "      <note><rest measure=\"yes\"/><duration>{}</duration>",
+                    "<voice>1</voice></note>\n",
+                ),
+                bar.timing.end - bar.timing.start
+            ));
+        } else {
+            for run in tuplet_runs(&cells) {
+                let triplet = run[0].value.triplet;
+                for (idx, cell) in run.iter().enumerate() {
+                    if marked {
+                        let timing = cell.start..(cell.start + cell.value.length());
+                        xml.push_str(&harmonies(score, timing, bar.fifths));
+                    }
+                    let tuplet = Tuplet {
+                        start: triplet && idx == 0,
+                        stop: triplet && idx + 1 == run.len(),
+                    };
+                    xml.push_str(&notes(cell, tuplet, part, &id, bar.fifths));
+                }
+            }
+        }
+
+        xml.push_str("    </measure>\n");
+        previous = Some(bar);
+    }
+    xml.push_str("  </part>\n");
+
+    xml
+}
+
+/// Divisions and clef for the first bar, along with key and time signatures wherever they change.
+fn attributes(bar: &Bar, previous: Option<&Bar>, clef: Clef) -> String {
+    let changes = bar.changes(previous);
+    let mut attributes = String::new();
+    if previous.is_none() {
+        attributes.push_str(&format!("<divisions>{}</divisions>", DIVISIONS));
+    }
+    if changes.key {
+        attributes.push_str(&format!("<key><fifths>{}</fifths></key>", bar.fifths));
+    }
+    if changes.time {
+        attributes.push_str(&format!(
+            "<time><beats>{}</beats><beat-type>{}</beat-type></time>",
+            bar.beats_per_bar, bar.beat_type
+        ));
+    }
+    if previous.is_none() {
+        attributes.push_str(match clef {
+            Clef::Treble => "<clef><sign>G</sign><line>2</line></clef>",
+            Clef::Bass => "<clef><sign>F</sign><line>4</line></clef>",
+            Clef::Percussion => "<clef><sign>percussion</sign></clef>",
+        });
+    }
+
+    if attributes.is_empty() {
+        attributes
+    } else {
+        format!("      <attributes>{}</attributes>\n", attributes)
+    }
+}
+
+/// Rehearsal marks for sections, and tempo markings wherever the tempo changes.
+fn directions(bar: &Bar, previous: Option<&Bar>) -> String {
+    let rehearsal = bar.rehearsal.as_ref().map(|mark| {
+        format!(
+            concat!(
+                "      <direction placement=\"above\"><direction-type>",
+                "<rehearsal>{}</rehearsal></direction-type></direction>\n",
+            ),
+            mark
+        )
+    });
+    // The sound tempo is always in quarter notes per minute
+    let tempo = Some(bar.bpm.round())
+        .filter(|_| bar.changes(previous).tempo)
+        .map(|bpm| {
+            format!(
+                concat!(
+                    "      <direction placement=\"above\"><direction-type><metronome>",
+                    "<beat-unit>{unit}</beat-unit><per-minute>{per_minute}</per-minute>",
+                    "</metronome></direction-type><sound tempo=\"{bpm}\"/></direction>\n",
+                ),
+                unit = note_type(bar.beat_type),
+                per_minute = bar.beat_bpm().round(),
+                bpm = bpm
+            )
+        });
+
+    rehearsal.into_iter().chain(tempo).collect()
+}
+
+/// Chord symbols starting within `timing`, offset from its start.
+fn harmonies(score: &Score, timing: Range<i32>, fifths: i32) -> String {
+    score
+        .chords_during(timing.clone())
+        .map(|(start, symbol)| {
+            let (step, alter) = spell(symbol.root, fifths);
+            let alter = Some(alter)
+                .filter(|alter| *alter != 0)
+                .map(|alter| format!("<root-alter>{}</root-alter>", alter))
+                .unwrap_or_default();
+            let offset = Some(start - timing.start)
+                .filter(|offset| *offset > 0)
+                .map(|offset| format!("<offset>{}</offset>", offset))
+                .unwrap_or_default();
+
+            format!(
+                concat!(
+                    "      <harmony><root><root-step>{}</root-step>{}</root>",
+                    "<kind>{}</kind>{}</harmony>\n",
+                ),
+                step,
+                alter,
+                kind(symbol.kind),
+                offset
+            )
+        })
+        .collect()
+}
+
+/// Whether a note starts or ends a tuplet bracket.
+#[derive(Debug, Copy, Clone)]
+struct Tuplet {
+    start: bool,
+    stop: bool,
+}
+
+/// A note element for each pitch of `cell` (chorded together), or a rest.
+fn notes(cell: &Cell, tuplet: Tuplet, part: &NotatedPart, part_id: &str, fifths: i32) -> String {
+    if cell.pitches.is_empty() {
+        return note(cell, tuplet, false, String::from("<rest/>"), "", None);
+    }
+
+    cell.pitches
+        .iter()
+        .enumerate()
+        .map(|(idx, pitch)| match part.instrument {
+            None => {
+                let (_, step, octave, notehead) = percussion(*pitch);
+                let unpitched = format!(
+                    concat!(
+                        "<unpitched><display-step>{}</display-step>",
+                        "<display-octave>{}</display-octave></unpitched>",
+                    ),
+                    step, octave
+                );
+                let instrument = format!("<instrument id=\"{}-I{}\"/>", part_id, pitch);
+
+                note(cell, tuplet, idx > 0, unpitched, &instrument, notehead)
+            }
+            Some(_) => {
+                let (step, alter) = spell(*pitch, fifths);
+                let alter = Some(alter)
+                    .filter(|alter| *alter != 0)
+                    .map(|alter| format!("<alter>{}</alter>", alter))
+                    .unwrap_or_default();
+                let pitched = format!(
+                    "<pitch><step>{}</step>{}<octave>{}</octave></pitch>",
+                    step,
+                    alter,
+                    *pitch as i32 / 12 - 1
+                );
+
+                note(cell, tuplet, idx > 0, pitched, "", None)
+            }
+        })
+        .collect()
+}
+
+/// Tuplet brackets are only marked on the first note of a chord.
+fn note(
+    cell: &Cell,
+    tuplet: Tuplet,
+    chord: bool,
+    content: String,
+    instrument: &str,
+    notehead: Option<&str>,
+) -> String {
+    let value = cell.value;
+    let mut xml = String::from("      <note>");
+    if chord {
+        xml.push_str("<chord/>");
+    }
+    xml.push_str(&content);
+    xml.push_str(&format!("<duration>{}</duration>", value.length()));
+    if cell.tied_from_previous {
+        xml.push_str("<tie type=\"stop\"/>");
+    }
+    if cell.tied_to_next {
+        xml.push_str("<tie type=\"start\"/>");
+    }
+    xml.push_str(instrument);
+    xml.push_str("<voice>1</voice>");
+    xml.push_str(&format!("<type>{}</type>", note_type(value.denominator)));
+    if value.dotted {
+        xml.push_str("<dot/>");
+    }
+    if value.triplet {
+        xml.push_str(concat!(
+            "<time-modification><actual-notes>3</actual-notes>",
+            "<normal-notes>2</normal-notes></time-modification>"
+        ));
+    }
+    if let Some(notehead) = notehead {
+        xml.push_str(&format!("<notehead>{}</notehead>", notehead));
+    }
+    let tuplet = Tuplet {
+        start: tuplet.start && !chord,
+        stop: tuplet.stop && !chord,
+    };
+    if cell.tied_from_previous || cell.tied_to_next || tuplet.start || tuplet.stop {
+        xml.push_str("<notations>");
+        if cell.tied_from_previous {
+            xml.push_str("<tied type=\"stop\"/>");
+        }
+        if cell.tied_to_next {
+            xml.push_str("<tied type=\"start\"/>");
+        }
+        if tuplet.start {
+            xml.push_str("<tuplet type=\"start\" bracket=\"yes\"/>");
+        }
+        if tuplet.stop {
+            xml.push_str("<tuplet type=\"stop\"/>");
+        }
+        xml.push_str("</notations>");
+    }
+    xml.push_str("</note>\n");
+
+    xml
+}
+
+fn note_type(denominator: i32) -> &'static str {
+    match denominator {
+        1 => "whole",
+        2 => "half",
+        4 => "quarter",
+        8 => "eighth",
+        16 => "16th",
+        32 => "32nd",
+        _ => "64th",
+    }
+}
+
+fn kind(kind: ChordKind) -> &'static str {
+    match kind {
+        ChordKind::Major => "major",
+        ChordKind::Minor => "minor",
+        ChordKind::Diminished => "diminished",
+        ChordKind::Augmented => "augmented",
+        ChordKind::SuspendedSecond => "suspended-second",
+        ChordKind::SuspendedFourth => "suspended-fourth",
+        ChordKind::MajorSixth => "major-sixth",
+        ChordKind::MinorSixth => "minor-sixth",
+        ChordKind::Dominant => "dominant",
+        ChordKind::MajorSeventh => "major-seventh",
+        ChordKind::MinorSeventh => "minor-seventh",
+        ChordKind::HalfDiminished => "half-diminished",
+        ChordKind::DiminishedSeventh => "diminished-seventh",
+        ChordKind::Power => "power",
+    }
+}
+
+fn drum_keys(part: &NotatedPart) -> Vec<u8> {
+    let mut keys = part
+        .events
+        .iter()
+        .flat_map(|event| event.pitches.iter().copied())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+
+    keys
+}
+
+/// Name, staff position and notehead of a General MIDI drum key in percussion notation.
+fn percussion(key: u8) -> (&'static str, char, i32, Option<&'static str>) {
+    match key {
+        35 => ("Acoustic Bass Drum", 'F', 4, None),
+        36 => ("Bass Drum", 'F', 4, None),
+        37 => ("Side Stick", 'C', 5, Some("x")),
+        38 => ("Acoustic Snare", 'C', 5, None),
+        39 => ("Hand Clap", 'C', 5, Some("slash")),
+        40 => ("Electric Snare", 'C', 5, None),
+        41 => ("Low Floor Tom", 'G', 4, None),
+        42 => ("Closed Hi-Hat", 'G', 5, Some("x")),
+        43 => ("High Floor Tom", 'A', 4, None),
+        44 => ("Pedal Hi-Hat", 'D', 4, Some("x")),
+        45 => ("Low Tom", 'B', 4, None),
+        46 => ("Open Hi-Hat", 'G', 5, Some("circle-x")),
+        47 => ("Low-Mid Tom", 'D', 5, None),
+        48 => ("Hi-Mid Tom", 'E', 5, None),
+        49 => ("Crash Cymbal 1", 'A', 5, Some("x")),
+        50 => ("High Tom", 'F', 5, None),
+        51 => ("Ride Cymbal 1", 'F', 5, Some("x")),
+        53 => ("Ride Bell", 'F', 5, Some("diamond")),
+        54 => ("Tambourine", 'B', 5, Some("triangle")),
+        55 => ("Splash Cymbal", 'B', 5, Some("x")),
+        57 => ("Crash Cymbal 2", 'A', 5, Some("x")),
+        59 => ("Ride Cymbal 2", 'F', 5, Some("x")),
+        _ => ("Percussion", 'E', 4, Some("triangle")),
+    }
+}
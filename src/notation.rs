@@ -0,0 +1,948 @@
+use crate::chord_progression::ChordMarkers;
+use crate::midi::descendants;
+use crate::mixing::{Mix, PartRole};
+use crate::structure::{PhraseDivider, Section};
+use redact_composer::elements::{Part, PlayNote};
+use redact_composer::midi::gm::elements::{DrumHit, Instrument};
+use redact_composer::musical::elements::{Chord, Key, TimeSignature};
+use redact_composer::musical::PitchClassCollection;
+use redact_composer::timing::elements::Tempo;
+use redact_composer::{Composition, Element};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Subdivisions of a beat that notated timings are measured in, fitting both sixteenths and eighth
+/// note triplets.
+pub const DIVISIONS: i32 = 12;
+/// Bars written per line of text formats.
+pub const BARS_PER_LINE: usize = 4;
+/// Positions within a beat (in [`DIVISIONS`]) of sixteenths, which timings may be quantized to.
+const DUPLE_GRID: [i32; 5] = [0, 3, 6, 9, 12];
+/// Positions within a beat (in [`DIVISIONS`]) of eighth note triplets, which timings may be
+/// quantized to.
+const TRIPLET_GRID: [i32; 4] = [0, 4, 8, 12];
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const SHARP_NAMES: [(char, i32); 12] = [
+    ('C', 0),
+    ('C', 1),
+    ('D', 0),
+    ('D', 1),
+    ('E', 0),
+    ('F', 0),
+    ('F', 1),
+    ('G', 0),
+    ('G', 1),
+    ('A', 0),
+    ('A', 1),
+    ('B', 0),
+];
+const FLAT_NAMES: [(char, i32); 12] = [
+    ('C', 0),
+    ('D', -1),
+    ('D', 0),
+    ('E', -1),
+    ('E', 0),
+    ('F', 0),
+    ('G', -1),
+    ('G', 0),
+    ('A', -1),
+    ('A', 0),
+    ('B', -1),
+    ('B', 0),
+];
+
+/// A composition reduced to what is needed for notation: its bars, chord symbols, and the quantized
+/// notes of each part.
+pub struct Score {
+    pub bars: Vec<Bar>,
+    pub chords: Vec<(i32, ChordSymbol)>,
+    pub parts: Vec<NotatedPart>,
+}
+
+pub struct Bar {
+    pub timing: Range<i32>,
+    pub beats_per_bar: i32,
+    /// The note value of a beat, e.g. `4` for quarter notes.
+    pub beat_type: i32,
+    /// Sharps in the key signature, or flats if negative.
+    pub fifths: i32,
+    pub bpm: f32,
+    /// Rehearsal mark of a [`Section`] starting in this bar.
+    pub rehearsal: Option<String>,
+}
+
+/// Markings that differ from the previous bar, and so need writing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Changes {
+    pub key: bool,
+    pub time: bool,
+    pub tempo: bool,
+}
+
+/// The notes of each section's [`Part`] with the same role and instrument (or drums).
+pub struct NotatedPart {
+    pub role: Option<PartRole>,
+    /// `None` for the drums.
+    pub instrument: Option<Instrument>,
+    /// Non-overlapping notes, with simultaneous notes grouped together.
+    pub events: Vec<Event>,
+}
+
+pub struct Event {
+    pub timing: Range<i32>,
+    /// Note numbers, or drum keys for the drums.
+    pub pitches: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Clef {
+    Treble,
+    Bass,
+    Percussion,
+}
+
+/// A single notehead (or rest) of some [`NoteValue`], possibly tied to its neighbours.
+pub struct Cell {
+    pub start: i32,
+    pub value: NoteValue,
+    /// Empty for rests.
+    pub pitches: Vec<u8>,
+    pub tied_from_previous: bool,
+    pub tied_to_next: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NoteValue {
+    /// The undotted note as a fraction of a whole note, e.g. `8` for an eighth note.
+    pub denominator: i32,
+    pub dotted: bool,
+    /// Played as one of three in the time of two.
+    pub triplet: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChordSymbol {
+    pub root: u8,
+    pub kind: ChordKind,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChordKind {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    SuspendedSecond,
+    SuspendedFourth,
+    MajorSixth,
+    MinorSixth,
+    Dominant,
+    MajorSeventh,
+    MinorSeventh,
+    HalfDiminished,
+    DiminishedSeventh,
+    Power,
+}
+
+impl Score {
+    pub fn of(composition: &Composition) -> Score {
+        let ticks_per_beat = composition.options.ticks_per_beat;
+        let to_divisions = |timing: &Range<i32>| {
+            let divisions = quantize(&[timing.start, timing.end], ticks_per_beat);
+
+            divisions[0]..divisions[1]
+        };
+
+        let keys = segments::<Key>(composition);
+        let tempos = segments::<Tempo>(composition);
+        let mut section_starts = segments::<Section>(composition)
+            .into_iter()
+            .map(|(timing, _)| timing.start)
+            .collect::<Vec<_>>();
+        section_starts.sort();
+        section_starts.dedup();
+        let phrases = segments::<PhraseDivider>(composition)
+            .into_iter()
+            .map(|(timing, _)| timing)
+            .collect::<Vec<_>>();
+
+        let mut signatures = segments::<TimeSignature>(composition);
+        signatures.sort_by_key(|(timing, _)| timing.start);
+        let bars = signatures
+            .iter()
+            .flat_map(|(timing, ts)| {
+                (timing.start..timing.end)
+                    .step_by(ts.bar().max(1) as usize)
+                    .map(move |start| (start..(start + ts.bar()).min(timing.end), *ts))
+            })
+            .map(|(timing, ts)| {
+                let key = keys.iter().find(|(t, _)| t.contains(&timing.start));
+                let tempo = tempos.iter().find(|(t, _)| t.contains(&timing.start));
+                let rehearsal = section_starts
+                    .iter()
+                    .position(|start| timing.contains(start))
+                    .map(rehearsal_mark);
+
+                Bar {
+                    timing: to_divisions(&timing),
+                    beats_per_bar: ts.beats_per_bar,
+                    beat_type: 4 * ticks_per_beat / ts.beat_length.max(1),
+                    fifths: key.map(|(_, key)| fifths(key)).unwrap_or(0),
+                    bpm: tempo.map(|(_, tempo)| tempo.bpm() as f32).unwrap_or(120.0),
+                    rehearsal,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut chords = composition
+            .tree
+            .iter()
+            .filter(|node| {
+                node.parent
+                    .and_then(|parent| {
+                        composition.tree[parent]
+                            .value
+                            .segment
+                            .element_as::<ChordMarkers>()
+                    })
+                    .is_some()
+            })
+            .filter_map(|node| {
+                let segment = &node.value.segment;
+
+                segment
+                    .element_as::<Chord>()
+                    .map(|chord| (segment.timing.start, ChordSymbol::of(chord)))
+            })
+            .collect::<Vec<_>>();
+        let starts = quantize(
+            &chords.iter().map(|(start, _)| *start).collect::<Vec<_>>(),
+            ticks_per_beat,
+        );
+        for ((start, _), quantized) in chords.iter_mut().zip(starts) {
+            *start = quantized;
+        }
+        chords.sort_by_key(|(start, _)| *start);
+        chords.dedup_by_key(|(start, _)| *start);
+
+        // Notes (in ticks) of each part, identified by role and instrument
+        let mut notes: Vec<(
+            (Option<PartRole>, Option<Instrument>),
+            Vec<(Range<i32>, u8)>,
+        )> = vec![];
+        let part_nodes = composition
+            .tree
+            .iter()
+            .filter(|node| node.value.segment.element_as::<Part>().is_some());
+        for node in part_nodes {
+            let segments = descendants(composition, node.idx)
+                .into_iter()
+                .map(|idx| &composition.tree[idx].value.segment)
+                .collect::<Vec<_>>();
+            let role = segments
+                .iter()
+                .find_map(|segment| segment.element_as::<Mix>())
+                .map(|mix| mix.role);
+            let percussion = segments
+                .iter()
+                .any(|segment| segment.element_as::<DrumHit>().is_some());
+            let instrument = segments
+                .iter()
+                .find_map(|segment| segment.element_as::<Instrument>())
+                .copied()
+                .filter(|_| !percussion);
+
+            let mut part_notes = segments
+                .iter()
+                .filter_map(|segment| {
+                    let timing = segment.timing.start..segment.timing.end;
+                    if let Some(hit) = segment.element_as::<DrumHit>() {
+                        Some((timing, u8::from(hit.hit)))
+                    } else {
+                        segment
+                            .element_as::<PlayNote>()
+                            .map(|play| (timing, play.note.0))
+                    }
+                })
+                .collect::<Vec<_>>();
+            if instrument.is_some() {
+                align(&mut part_notes, &phrases);
+            }
+
+            let identity = (role, instrument);
+            match notes.iter_mut().find(|(existing, _)| *existing == identity) {
+                Some((_, existing)) => existing.extend(part_notes),
+                None => notes.push((identity, part_notes)),
+            }
+        }
+
+        let mut parts = notes
+            .into_iter()
+            .map(|((role, instrument), notes)| {
+                let ticks = notes
+                    .iter()
+                    .flat_map(|(timing, _)| [timing.start, timing.end])
+                    .collect::<Vec<_>>();
+                let divisions = quantize(&ticks, ticks_per_beat);
+                let notes = notes
+                    .iter()
+                    .zip(divisions.chunks(2))
+                    .map(|((_, pitch), timing)| (timing[0]..timing[1], *pitch))
+                    // Ornaments too short to quantize are left out
+                    .filter(|(timing, _)| timing.end > timing.start)
+                    .collect();
+
+                NotatedPart {
+                    role,
+                    instrument,
+                    events: events(notes),
+                }
+            })
+            .filter(|part| !part.events.is_empty())
+            .collect::<Vec<_>>();
+        // Pitched parts in order of entry, followed by the drums
+        parts.sort_by_key(|part| {
+            (
+                part.is_percussion(),
+                part.events.first().map(|event| event.timing.start),
+            )
+        });
+
+        Score {
+            bars,
+            chords,
+            parts,
+        }
+    }
+
+    /// Lays out `events` in each bar, filling gaps with rests and splitting notes which don't fit a
+    /// single note value or bar into tied cells.
+    pub fn cells(&self, events: &[Event]) -> Vec<Vec<Cell>> {
+        self.bars
+            .iter()
+            .map(|bar| {
+                let mut cells = vec![];
+                let mut cursor = bar.timing.start;
+                for event in events.iter().filter(|event| {
+                    event.timing.start < bar.timing.end && event.timing.end > bar.timing.start
+                }) {
+                    let timing =
+                        event.timing.start.max(cursor)..event.timing.end.min(bar.timing.end);
+                    push_cells(&mut cells, cursor..timing.start, &[], false, false);
+                    push_cells(
+                        &mut cells,
+                        timing.clone(),
+                        &event.pitches,
+                        event.timing.start < timing.start,
+                        event.timing.end > timing.end,
+                    );
+                    cursor = timing.end;
+                }
+                push_cells(&mut cells, cursor..bar.timing.end, &[], false, false);
+
+                cells
+            })
+            .collect()
+    }
+
+    /// The chord symbols as events, each lasting until the next and sounding its root.
+    pub fn chord_events(&self) -> Vec<Event> {
+        let end = self.bars.last().map(|bar| bar.timing.end).unwrap_or(0);
+        let ends = self
+            .chords
+            .iter()
+            .skip(1)
+            .map(|(start, _)| *start)
+            .chain([end]);
+
+        self.chords
+            .iter()
+            .zip(ends)
+            .map(|((start, symbol), end)| Event {
+                timing: *start..end,
+                pitches: vec![symbol.root],
+            })
+            .collect()
+    }
+
+    /// Chord symbols starting within `timing`.
+    pub fn chords_during(&self, timing: Range<i32>) -> impl Iterator<Item = &(i32, ChordSymbol)> {
+        self.chords
+            .iter()
+            .filter(move |(start, _)| timing.contains(start))
+    }
+}
+
+impl Bar {
+    /// The tempo counted in beats of [`Bar::beat_type`], rather than quarter notes.
+    pub fn beat_bpm(&self) -> f32 {
+        self.bpm * self.beat_type as f32 / 4.0
+    }
+
+    /// Markings changed since `previous`, or all of them for the first bar.
+    pub fn changes(&self, previous: Option<&Bar>) -> Changes {
+        match previous {
+            Some(prev) => Changes {
+                key: prev.fifths != self.fifths,
+                time: (prev.beats_per_bar, prev.beat_type) != (self.beats_per_bar, self.beat_type),
+                tempo: prev.bpm.round() != self.bpm.round(),
+            },
+            None => Changes {
+                key: true,
+                time: true,
+                tempo: true,
+            },
+        }
+    }
+}
+
+impl NotatedPart {
+    pub fn is_percussion(&self) -> bool {
+        self.instrument.is_none()
+    }
+
+    /// The instrument, followed by the role for pitched parts, e.g. `Violin (extra1)`.
+    pub fn name(&self) -> String {
+        match (self.instrument, self.role) {
+            (Some(instrument), Some(role)) => format!("{:?} ({})", instrument, role.name()),
+            (Some(instrument), None) => format!("{:?}", instrument),
+            (None, _) => String::from("Drums"),
+        }
+    }
+
+    pub fn clef(&self) -> Clef {
+        let pitches = self.events.iter().flat_map(|event| &event.pitches);
+        let (sum, count) = pitches.fold((0, 0), |(sum, count), p| (sum + *p as i32, count + 1));
+
+        match self.instrument {
+            None => Clef::Percussion,
+            Some(_) if count > 0 && sum / count < 55 => Clef::Bass,
+            Some(_) => Clef::Treble,
+        }
+    }
+}
+
+impl Clef {
+    /// The clef's name in LilyPond and ABC, which only write pitched parts.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Clef::Bass => "bass",
+            Clef::Treble | Clef::Percussion => "treble",
+        }
+    }
+}
+
+impl NoteValue {
+    /// Every note value with a length expressible in [`DIVISIONS`], longest first.
+    const ALL: [NoteValue; 13] = [
+        NoteValue::new(1, true, false),
+        NoteValue::new(1, false, false),
+        NoteValue::new(2, true, false),
+        NoteValue::new(2, false, false),
+        NoteValue::new(4, true, false),
+        NoteValue::new(2, false, true),
+        NoteValue::new(4, false, false),
+        NoteValue::new(8, true, false),
+        NoteValue::new(4, false, true),
+        NoteValue::new(8, false, false),
+        NoteValue::new(8, false, true),
+        NoteValue::new(16, false, false),
+        NoteValue::new(16, false, true),
+    ];
+
+    pub const fn new(denominator: i32, dotted: bool, triplet: bool) -> NoteValue {
+        NoteValue {
+            denominator,
+            dotted,
+            triplet,
+        }
+    }
+
+    /// Length in [`DIVISIONS`] of a quarter note beat.
+    pub fn length(&self) -> i32 {
+        let len = 4 * DIVISIONS / self.denominator;
+        let len = if self.dotted { len * 3 / 2 } else { len };
+
+        if self.triplet {
+            len * 2 / 3
+        } else {
+            len
+        }
+    }
+
+    /// Splits `timing` into note values at each beat it crosses.
+    pub fn split(timing: Range<i32>) -> Vec<NoteValue> {
+        let mut values = vec![];
+        let mut start = timing.start;
+        while start < timing.end {
+            let next_beat = (start.div_euclid(DIVISIONS) + 1) * DIVISIONS;
+            let whole_beats = (timing.end - start) / DIVISIONS * DIVISIONS;
+            let value = if start % DIVISIONS == 0 && whole_beats > 0 {
+                NoteValue::ALL
+                    .into_iter()
+                    .find(|value| value.length() % DIVISIONS == 0 && value.length() <= whole_beats)
+            } else {
+                let len = next_beat.min(timing.end) - start;
+                NoteValue::ALL
+                    .into_iter()
+                    .find(|value| value.length() <= len)
+            };
+            // Remainders shorter than any note value are dropped
+            let Some(value) = value else {
+                break;
+            };
+
+            values.push(value);
+            start += value.length();
+        }
+
+        values
+    }
+}
+
+impl ChordKind {
+    /// The suffix naming the chord after its root, e.g. `m7` for [`ChordKind::MinorSeventh`].
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ChordKind::Major => "",
+            ChordKind::Minor => "m",
+            ChordKind::Diminished => "dim",
+            ChordKind::Augmented => "aug",
+            ChordKind::SuspendedSecond => "sus2",
+            ChordKind::SuspendedFourth => "sus4",
+            ChordKind::MajorSixth => "6",
+            ChordKind::MinorSixth => "m6",
+            ChordKind::Dominant => "7",
+            ChordKind::MajorSeventh => "maj7",
+            ChordKind::MinorSeventh => "m7",
+            ChordKind::HalfDiminished => "m7b5",
+            ChordKind::DiminishedSeventh => "dim7",
+            ChordKind::Power => "5",
+        }
+    }
+}
+
+impl ChordSymbol {
+    pub fn of(chord: &Chord) -> ChordSymbol {
+        let root = chord.root().0 % 12;
+        let mut intervals = chord
+            .pitch_classes()
+            .iter()
+            .map(|pc| (pc.0 % 12 + 12 - root) % 12)
+            .collect::<Vec<_>>();
+        intervals.sort();
+        intervals.dedup();
+
+        let kind = match intervals[..] {
+            [0, 4, 7] => ChordKind::Major,
+            [0, 3, 7] => ChordKind::Minor,
+            [0, 3, 6] => ChordKind::Diminished,
+            [0, 4, 8] => ChordKind::Augmented,
+            [0, 2, 7] => ChordKind::SuspendedSecond,
+            [0, 5, 7] => ChordKind::SuspendedFourth,
+            [0, 4, 7, 9] => ChordKind::MajorSixth,
+            [0, 3, 7, 9] => ChordKind::MinorSixth,
+            [0, 4, 7, 10] => ChordKind::Dominant,
+            [0, 4, 7, 11] => ChordKind::MajorSeventh,
+            [0, 3, 7, 10] => ChordKind::MinorSeventh,
+            [0, 3, 6, 10] => ChordKind::HalfDiminished,
+            [0, 3, 6, 9] => ChordKind::DiminishedSeventh,
+            // Otherwise named by its third, if any
+            _ if intervals.contains(&4) => ChordKind::Major,
+            _ if intervals.contains(&3) => ChordKind::Minor,
+            _ => ChordKind::Power,
+        };
+
+        ChordSymbol { root, kind }
+    }
+}
+
+/// Splits a bar's cells into runs of other notes and groups of triplets, so each group can be
+/// notated as a tuplet.
+pub fn tuplet_runs(cells: &[Cell]) -> Vec<&[Cell]> {
+    let origin = cells.first().map(|cell| cell.start).unwrap_or(0);
+
+    let mut runs = vec![];
+    let mut remaining = cells;
+    while let Some(first) = remaining.first() {
+        let len = if first.value.triplet {
+            let span = 3 * first.value.length();
+            let end = origin + ((first.start - origin).div_euclid(span) + 1) * span;
+
+            remaining
+                .iter()
+                .take_while(|cell| cell.value.triplet && cell.start < end)
+                .count()
+        } else {
+            remaining
+                .iter()
+                .take_while(|cell| !cell.value.triplet)
+                .count()
+        };
+        let (run, rest) = remaining.split_at(len);
+        runs.push(run);
+        remaining = rest;
+    }
+
+    runs
+}
+
+/// The step and alteration naming `pitch`, using sharps or flats to suit the key signature.
+pub fn spell(pitch: u8, fifths: i32) -> (char, i32) {
+    if fifths < 0 {
+        FLAT_NAMES[(pitch % 12) as usize]
+    } else {
+        SHARP_NAMES[(pitch % 12) as usize]
+    }
+}
+
+/// The step and alteration naming the major key of a key signature.
+pub fn key_tonic(fifths: i32) -> (char, i32) {
+    spell((fifths * 7).rem_euclid(12) as u8, fifths)
+}
+
+/// Converts `ticks` to [`DIVISIONS`].
+fn quantize(ticks: &[i32], ticks_per_beat: i32) -> Vec<i32> {
+    let positions = ticks
+        .iter()
+        .map(|tick| {
+            let beat = tick.div_euclid(ticks_per_beat);
+            let offset = tick.rem_euclid(ticks_per_beat) as f32 * DIVISIONS as f32
+                / ticks_per_beat.max(1) as f32;
+
+            (beat, offset)
+        })
+        .collect::<Vec<_>>();
+
+    let mut errors: BTreeMap<i32, (f32, f32)> = BTreeMap::new();
+    for (beat, offset) in &positions {
+        let (duple, triplet) = errors.entry(*beat).or_default();
+        *duple += (snap(*offset, &DUPLE_GRID) as f32 - offset).abs();
+        *triplet += (snap(*offset, &TRIPLET_GRID) as f32 - offset).abs();
+    }
+
+    positions
+        .into_iter()
+        .map(|(beat, offset)| {
+            let grid: &[i32] = match errors.get(&beat) {
+                Some((duple, triplet)) if triplet < duple => &TRIPLET_GRID,
+                _ => &DUPLE_GRID,
+            };
+
+            beat * DIVISIONS + snap(offset, grid)
+        })
+        .collect()
+}
+
+/// The position in `grid` nearest to `offset`.
+fn snap(offset: f32, grid: &[i32]) -> i32 {
+    grid.iter()
+        .copied()
+        .min_by(|a, b| {
+            (*a as f32 - offset)
+                .abs()
+                .total_cmp(&(*b as f32 - offset).abs())
+        })
+        .unwrap_or(0)
+}
+
+/// The key signature (in sharps, or flats if negative) sharing the most pitches with `key`.
+fn fifths(key: &Key) -> i32 {
+    let pitches = key
+        .pitch_classes()
+        .iter()
+        .map(|pc| pc.0 % 12)
+        .collect::<Vec<_>>();
+
+    (-7..=7)
+        .max_by_key(|fifths: &i32| {
+            let tonic = (fifths * 7).rem_euclid(12) as u8;
+            let shared = MAJOR_SCALE
+                .iter()
+                .filter(|step| pitches.contains(&((tonic + *step) % 12)))
+                .count();
+
+            (shared, -fifths.abs())
+        })
+        .unwrap_or(0)
+}
+
+/// Letters `A` through `Z`, then `AA`, `BB` and so on.
+fn rehearsal_mark(idx: usize) -> String {
+    char::from(b'A' + (idx % 26) as u8)
+        .to_string()
+        .repeat(idx / 26 + 1)
+}
+
+fn segments<T: Element>(composition: &Composition) -> Vec<(Range<i32>, &T)> {
+    composition
+        .tree
+        .iter()
+        .filter_map(|node| {
+            let segment = &node.value.segment;
+
+            segment
+                .element_as::<T>()
+                .map(|element| (segment.timing.start..segment.timing.end, element))
+        })
+        .collect()
+}
+
+/// Groups notes sharing a start, each lasting until the next starts at the latest.
+fn events(mut notes: Vec<(Range<i32>, u8)>) -> Vec<Event> {
+    notes.sort_by_key(|(timing, pitch)| (timing.start, *pitch));
+
+    let mut events: Vec<Event> = vec![];
+    for (timing, pitch) in notes {
+        match events.last_mut() {
+            Some(event) if event.timing.start == timing.start => {
+                event.timing.end = event.timing.end.max(timing.end);
+                if !event.pitches.contains(&pitch) {
+                    event.pitches.push(pitch);
+                }
+            }
+            last => {
+                if let Some(event) = last {
+                    event.timing.end = event.timing.end.min(timing.start);
+                }
+                events.push(Event {
+                    timing,
+                    pitches: vec![pitch],
+                });
+            }
+        }
+    }
+
+    events
+}
+
+/// Ends each note by the end of the phrase it starts in, if any.
+fn align(notes: &mut [(Range<i32>, u8)], phrases: &[Range<i32>]) {
+    for (timing, _) in notes {
+        if let Some(phrase) = phrases.iter().find(|phrase| phrase.contains(&timing.start)) {
+            timing.end = timing.end.min(phrase.end);
+        }
+    }
+}
+
+fn push_cells(
+    cells: &mut Vec<Cell>,
+    timing: Range<i32>,
+    pitches: &[u8],
+    tied_from_previous: bool,
+    tied_to_next: bool,
+) {
+    let values = NoteValue::split(timing.clone());
+    let (count, sounding) = (values.len(), !pitches.is_empty());
+
+    let mut start = timing.start;
+    for (idx, value) in values.into_iter().enumerate() {
+        cells.push(Cell {
+            start,
+            value,
+            pitches: pitches.to_vec(),
+            tied_from_previous: sounding && (idx > 0 || tied_from_previous),
+            tied_to_next: sounding && (idx + 1 < count || tied_to_next),
+        });
+        start += value.length();
+    }
+}
+
+/// Cells and note values shared by the tests of the notation writers.
+#[cfg(test)]
+pub mod fixtures {
+    use super::{Cell, NoteValue};
+
+    pub const QUARTER: NoteValue = NoteValue::new(4, false, false);
+    pub const EIGHTH: NoteValue = NoteValue::new(8, false, false);
+    pub const EIGHTH_TRIPLET: NoteValue = NoteValue::new(8, false, true);
+
+    /// An untied cell of `pitches`, or a rest if there are none.
+    pub fn cell(start: i32, value: NoteValue, pitches: &[u8]) -> Cell {
+        Cell {
+            start,
+            value,
+            pitches: pitches.to_vec(),
+            tied_from_previous: false,
+            tied_to_next: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+    use redact_composer::musical::elements::{Mode, Scale};
+    use redact_composer::musical::{ChordShape, PitchClass};
+
+    #[test]
+    fn split_keeps_whole_beats_together() {
+        assert_eq!(NoteValue::split(0..12), vec![QUARTER]);
+        assert_eq!(
+            NoteValue::split(0..36),
+            vec![NoteValue::new(2, true, false)]
+        );
+        assert_eq!(
+            NoteValue::split(0..30),
+            vec![NoteValue::new(2, false, false), EIGHTH]
+        );
+        // Not a dotted quarter, which would end mid-beat
+        assert_eq!(NoteValue::split(0..18), vec![QUARTER, EIGHTH]);
+    }
+
+    #[test]
+    fn split_at_beat_boundaries() {
+        assert_eq!(NoteValue::split(6..18), vec![EIGHTH, EIGHTH]);
+        assert_eq!(NoteValue::split(8..24), vec![EIGHTH_TRIPLET, QUARTER]);
+        assert_eq!(
+            NoteValue::split(3..12),
+            vec![NoteValue::new(8, true, false)]
+        );
+        assert_eq!(NoteValue::split(0..8), vec![NoteValue::new(4, false, true)]);
+    }
+
+    #[test]
+    fn split_drops_remainders_without_note_value() {
+        assert!(NoteValue::split(0..1).is_empty());
+        assert!(NoteValue::split(5..5).is_empty());
+    }
+
+    #[test]
+    fn quantize_snaps_each_beat_to_one_grid() {
+        // 40 ticks per division
+        assert_eq!(quantize(&[0, 160, 320], 480), vec![0, 4, 8]);
+        assert_eq!(quantize(&[480, 600, 840], 480), vec![12, 15, 21]);
+        // Closer to sixteenths overall, so the eighth note triplet snaps to a sixteenth instead
+        assert_eq!(quantize(&[120, 330], 480), vec![3, 9]);
+        assert_eq!(quantize(&[165, 315], 480), vec![4, 8]);
+    }
+
+    #[test]
+    fn bar_changes_are_relative_to_previous() {
+        let bar = |fifths: i32, beats_per_bar: i32, bpm: f32| Bar {
+            timing: 0..48,
+            beats_per_bar,
+            beat_type: 4,
+            fifths,
+            bpm,
+            rehearsal: None,
+        };
+        let first = bar(0, 4, 120.0);
+
+        assert_eq!(
+            first.changes(None),
+            Changes {
+                key: true,
+                time: true,
+                tempo: true
+            }
+        );
+        assert_eq!(
+            bar(-1, 4, 120.2).changes(Some(&first)),
+            Changes {
+                key: true,
+                time: false,
+                tempo: false
+            }
+        );
+        assert_eq!(
+            bar(0, 3, 126.0).changes(Some(&first)),
+            Changes {
+                key: false,
+                time: true,
+                tempo: true
+            }
+        );
+    }
+
+    #[test]
+    fn quantize_chooses_grids_per_beat() {
+        assert_eq!(quantize(&[160, 600], 480), vec![4, 15]);
+        // Snapping to the end of a beat moves to the next
+        assert_eq!(quantize(&[470], 480), vec![12]);
+        assert_eq!(quantize(&[-40], 480), vec![0]);
+    }
+
+    fn run_starts(cells: &[Cell]) -> Vec<Vec<i32>> {
+        tuplet_runs(cells)
+            .into_iter()
+            .map(|run| run.iter().map(|cell| cell.start).collect())
+            .collect()
+    }
+
+    #[test]
+    fn tuplet_runs_group_eighth_triplets_per_beat() {
+        let cells = [
+            cell(0, EIGHTH_TRIPLET, &[60]),
+            cell(4, EIGHTH_TRIPLET, &[60]),
+            cell(8, EIGHTH_TRIPLET, &[60]),
+            cell(12, EIGHTH_TRIPLET, &[60]),
+            cell(16, NoteValue::new(4, false, true), &[60]),
+            cell(24, QUARTER, &[60]),
+            cell(36, EIGHTH, &[60]),
+            cell(42, EIGHTH, &[60]),
+        ];
+
+        assert_eq!(
+            run_starts(&cells),
+            vec![vec![0, 4, 8], vec![12, 16], vec![24, 36, 42]]
+        );
+    }
+
+    #[test]
+    fn tuplet_runs_group_quarter_triplets_per_two_beats() {
+        let quarter_triplet = NoteValue::new(4, false, true);
+        let cells = [
+            cell(12, quarter_triplet, &[60]),
+            cell(20, EIGHTH_TRIPLET, &[60]),
+            cell(24, EIGHTH_TRIPLET, &[60]),
+            cell(28, EIGHTH_TRIPLET, &[60]),
+            cell(32, EIGHTH_TRIPLET, &[60]),
+            cell(36, quarter_triplet, &[60]),
+            cell(44, EIGHTH_TRIPLET, &[60]),
+        ];
+
+        // Measured from the bar's start at 12, the first group spans its first two beats
+        assert_eq!(
+            run_starts(&cells),
+            vec![vec![12, 20, 24, 28, 32], vec![36, 44]]
+        );
+    }
+
+    #[test]
+    fn chord_symbols_name_triads() {
+        let key = Key::from((PitchClass(0), Scale::Major, Mode::Ionian));
+        let symbol = |root: u8| {
+            let chord = key
+                .chords_with_shape(ChordShape::triad())
+                .into_iter()
+                .find(|chord| chord.root() == PitchClass(root))
+                .unwrap();
+
+            ChordSymbol::of(&chord)
+        };
+
+        assert_eq!(
+            symbol(0),
+            ChordSymbol {
+                root: 0,
+                kind: ChordKind::Major
+            }
+        );
+        assert_eq!(
+            symbol(2),
+            ChordSymbol {
+                root: 2,
+                kind: ChordKind::Minor
+            }
+        );
+        assert_eq!(
+            symbol(11),
+            ChordSymbol {
+                root: 11,
+                kind: ChordKind::Diminished
+            }
+        );
+    }
+}
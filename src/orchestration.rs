@@ -1,18 +1,26 @@
+use crate::style::Style;
 use rand::prelude::SliceRandom;
+use rand::Rng;
+use redact_composer::error::RendererError;
 use redact_composer::error::RendererError::MissingContext;
 use redact_composer::midi::elements::DrumKit;
 use redact_composer::midi::gm::elements::Instrument;
 use redact_composer::midi::gm::Instruments;
+use redact_composer::musical::{Interval, Note};
+use redact_composer::render::context::CompositionContext;
+use redact_composer::render::context::TimingRelation::During;
 use redact_composer::render::{AdhocRenderer, RenderEngine};
 use redact_composer::util::IntoSegment;
 use redact_composer::{Element, Renderer};
 use serde::{Deserialize, Serialize};
+use std::iter::once;
+use std::ops::{Range, RangeInclusive};
 
 pub fn renderers() -> RenderEngine {
     RenderEngine::new() + RandomInstrumentation::renderer()
 }
 
-#[derive(Element, Serialize, Deserialize, Debug)]
+#[derive(Element, Serialize, Deserialize, Debug, Clone)]
 pub struct Instrumentation {
     pub drums: DrumKit,
     pub bass: Instrument,
@@ -21,6 +29,29 @@ pub struct Instrumentation {
 }
 
 impl Instrumentation {
+    /// Looks up the [`Instrumentation`] during `timing`, with any [`InstrumentationChange`] during
+    /// the same timing applied.
+    pub fn during(
+        ctx: &CompositionContext,
+        timing: Range<i32>,
+    ) -> Result<Instrumentation, RendererError> {
+        let instrumentation = ctx
+            .find::<Instrumentation>()
+            .with_timing(During, timing.clone())
+            .require()?
+            .element
+            .clone();
+        let change = ctx
+            .find::<InstrumentationChange>()
+            .with_timing(During, timing)
+            .get();
+
+        Ok(match change {
+            Some(change) => change.element.apply(instrumentation),
+            None => instrumentation,
+        })
+    }
+
     pub fn melody_instruments() -> Vec<Instrument> {
         Instruments::melodic().into()
     }
@@ -49,42 +80,117 @@ impl Instrumentation {
     }
 }
 
-#[derive(Element, Serialize, Deserialize, Debug)]
-pub struct RandomInstrumentation;
+/// Chooses an [`Instrumentation`] whose instruments sound coherent together.
+#[derive(Element, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RandomInstrumentation {
+    pub melody: Option<Instrument>,
+    pub bass: Option<Instrument>,
+    pub drums: Option<DrumKit>,
+    /// Extra instruments always included, in addition to any chosen.
+    pub extras: Vec<Instrument>,
+    /// The range of the total number of extra instruments, two if not provided.
+    pub extras_count: Option<RangeInclusive<usize>>,
+    pub melody_pool: Vec<Instrument>,
+    pub bass_pool: Vec<Instrument>,
+    pub drum_pool: Vec<DrumKit>,
+}
 
 impl RandomInstrumentation {
     pub fn renderer() -> impl Renderer<Element = Self> {
         AdhocRenderer::<Self>::new(|segment, ctx| {
             let mut rng = ctx.rng();
+            let options = segment.element;
+            let style = Style::during(ctx, segment.timing.start..segment.timing.end);
+            let pool =
+                |pool: &[Instrument], style_pool: &[Instrument], default: Vec<Instrument>| {
+                    if !pool.is_empty() {
+                        pool.to_vec()
+                    } else if !style_pool.is_empty() {
+                        style_pool.to_vec()
+                    } else {
+                        default
+                    }
+                };
+            let melody_pool = pool(
+                &options.melody_pool,
+                &style.melody_instruments,
+                Instrumentation::melody_instruments(),
+            );
+            let bass_pool = pool(
+                &options.bass_pool,
+                &style.bass_instruments,
+                Instrumentation::bass_instruments(),
+            );
+            let mut chosen = options
+                .melody
+                .into_iter()
+                .chain(options.bass)
+                .chain(options.extras.iter().copied())
+                .collect::<Vec<_>>();
 
-            let melody = Instrumentation::melody_instruments()
-                .choose(&mut rng)
-                .copied()
-                .ok_or(MissingContext(String::from(
-                    "No available melody instruments.",
-                )))?;
+            // Pinned instruments are already chosen, so only drawn ones are added
+            let melody = match options.melody {
+                Some(melody) => melody,
+                None => {
+                    let melody = Compatibility::choose(&melody_pool, &chosen, &mut rng).ok_or(
+                        MissingContext(String::from("No available melody instruments.")),
+                    )?;
+                    chosen.push(melody);
 
-            let bass = Instrumentation::bass_instruments()
-                .choose(&mut rng)
-                .copied()
-                .ok_or(MissingContext(String::from(
-                    "No available bass instruments.",
-                )))?;
+                    melody
+                }
+            };
+
+            let bass = match options.bass {
+                Some(bass) => bass,
+                None => {
+                    let candidates = bass_pool
+                        .into_iter()
+                        .filter(|i| *i != melody)
+                        .collect::<Vec<_>>();
+                    let bass = Compatibility::choose(&candidates, &chosen, &mut rng).ok_or(
+                        MissingContext(String::from("No available bass instruments.")),
+                    )?;
+                    chosen.push(bass);
+
+                    bass
+                }
+            };
 
-            let drums = Instrumentation::drum_instruments()
-                .choose(&mut rng)
-                .copied()
+            let drums = options
+                .drums
+                .or_else(|| {
+                    let drum_pool = if !options.drum_pool.is_empty() {
+                        &options.drum_pool
+                    } else {
+                        &style.drum_kits
+                    };
+
+                    Style::choose_from(drum_pool, &Instrumentation::drum_instruments(), &mut rng)
+                })
                 .ok_or(MissingContext(String::from(
                     "No available drum instruments.",
                 )))?;
 
-            let extras = Instrumentation::melody_instruments()
-                .into_iter()
-                .filter(|i| i != &melody)
-                .collect::<Vec<_>>()
-                .choose_multiple(&mut rng, 2)
-                .copied()
-                .collect::<Vec<_>>();
+            let extras_count = options
+                .extras_count
+                .clone()
+                .filter(|count| !count.is_empty())
+                .map(|count| rng.gen_range(count))
+                .unwrap_or(2);
+            let mut extras = options.extras.clone();
+            while extras.len() < extras_count {
+                let candidates = melody_pool
+                    .iter()
+                    .copied()
+                    .filter(|i| *i != melody && *i != bass && !extras.contains(i))
+                    .collect::<Vec<_>>();
+                let Some(extra) = Compatibility::choose(&candidates, &chosen, &mut rng) else {
+                    break;
+                };
+                extras.push(extra);
+                chosen.push(extra);
+            }
 
             Ok(vec![Instrumentation {
                 drums,
@@ -97,5 +203,421 @@ impl RandomInstrumentation {
     }
 }
 
+/// Changes to the composition's [`Instrumentation`] for the span of a
+/// [`Section`](crate::structure::Section).
+#[derive(Element, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstrumentationChange {
+    pub melody: Option<Instrument>,
+    pub bass: Option<Instrument>,
+    pub extras: Option<Vec<Instrument>>,
+}
+
+impl InstrumentationChange {
+    pub fn apply(&self, instrumentation: Instrumentation) -> Instrumentation {
+        Instrumentation {
+            melody: self.melody.unwrap_or(instrumentation.melody),
+            bass: self.bass.unwrap_or(instrumentation.bass),
+            extras: self.extras.clone().unwrap_or(instrumentation.extras),
+            ..instrumentation
+        }
+    }
+
+    /// Sometimes introduces a new lead compatible with the rest of `instrumentation`, or thins
+    /// out its extras.
+    pub fn random(
+        instrumentation: &Instrumentation,
+        style: &Style,
+        rng: &mut impl Rng,
+    ) -> Option<InstrumentationChange> {
+        if !rng.gen_bool(0.3) {
+            return None;
+        }
+
+        if rng.gen_bool(0.6) {
+            let pool = if style.melody_instruments.is_empty() {
+                Instrumentation::melody_instruments()
+            } else {
+                style.melody_instruments.clone()
+            };
+            let others = once(instrumentation.bass)
+                .chain(instrumentation.extras.iter().copied())
+                .collect::<Vec<_>>();
+            let candidates = pool
+                .into_iter()
+                .filter(|i| !others.contains(i) && *i != instrumentation.melody)
+                .collect::<Vec<_>>();
+
+            Compatibility::choose(&candidates, &others, rng).map(|melody| InstrumentationChange {
+                melody: Some(melody),
+                ..Default::default()
+            })
+        } else {
+            let count = rng.gen_range(0..instrumentation.extras.len().max(1));
+
+            Some(InstrumentationChange {
+                extras: Some(
+                    instrumentation
+                        .extras
+                        .choose_multiple(rng, count)
+                        .copied()
+                        .collect(),
+                ),
+                ..Default::default()
+            })
+        }
+    }
+}
+
 #[derive(Element, Serialize, Deserialize, Debug)]
 pub struct PartArrangement;
+
+/// General MIDI instrument families, each spanning eight consecutive programs.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InstrumentFamily {
+    Piano,
+    ChromaticPercussion,
+    Organ,
+    Guitar,
+    Bass,
+    Strings,
+    Ensemble,
+    Brass,
+    Reed,
+    Pipe,
+    SynthLead,
+    SynthPad,
+    SynthEffects,
+    Ethnic,
+    Percussive,
+    SoundEffects,
+}
+
+impl InstrumentFamily {
+    pub fn of(instrument: Instrument) -> InstrumentFamily {
+        [
+            (Instruments::piano(), InstrumentFamily::Piano),
+            (
+                Instruments::chromatic_percussion(),
+                InstrumentFamily::ChromaticPercussion,
+            ),
+            (Instruments::organ(), InstrumentFamily::Organ),
+            (Instruments::guitar(), InstrumentFamily::Guitar),
+            (Instruments::bass(), InstrumentFamily::Bass),
+            (Instruments::strings(), InstrumentFamily::Strings),
+            (Instruments::ensemble(), InstrumentFamily::Ensemble),
+            (Instruments::brass(), InstrumentFamily::Brass),
+            (Instruments::reed(), InstrumentFamily::Reed),
+            (Instruments::pipe(), InstrumentFamily::Pipe),
+            (Instruments::synth_lead(), InstrumentFamily::SynthLead),
+            (Instruments::synth_pad(), InstrumentFamily::SynthPad),
+            (Instruments::synth_effects(), InstrumentFamily::SynthEffects),
+            (Instruments::ethnic(), InstrumentFamily::Ethnic),
+            (Instruments::percussive(), InstrumentFamily::Percussive),
+            (Instruments::sound_effects(), InstrumentFamily::SoundEffects),
+        ]
+        .into_iter()
+        .map(|(instruments, family)| (Vec::<Instrument>::from(instruments), family))
+        .find(|(instruments, _)| instruments.contains(&instrument))
+        .map(|(_, family)| family)
+        .unwrap_or(InstrumentFamily::SoundEffects)
+    }
+}
+
+/// The note ranges an instrument can play (`playable`) and sounds best in (`comfortable`).
+#[derive(Debug, Clone)]
+pub struct InstrumentRange {
+    pub playable: RangeInclusive<Note>,
+    pub comfortable: RangeInclusive<Note>,
+}
+
+impl InstrumentRange {
+    fn new(playable: RangeInclusive<u8>, comfortable: RangeInclusive<u8>) -> InstrumentRange {
+        InstrumentRange {
+            playable: Note(*playable.start())..=Note(*playable.end()),
+            comfortable: Note(*comfortable.start())..=Note(*comfortable.end()),
+        }
+    }
+
+    /// Looks up the range of an instrument, using its family's range unless the instrument differs
+    /// notably from the rest of its family.
+    pub fn of(instrument: Instrument) -> InstrumentRange {
+        match instrument {
+            Instrument::Violin => Self::new(55..=103, 60..=93),
+            Instrument::Viola => Self::new(48..=91, 53..=81),
+            Instrument::Cello => Self::new(36..=76, 40..=69),
+            Instrument::Contrabass => Self::new(28..=67, 28..=55),
+            Instrument::Piccolo => Self::new(74..=108, 79..=103),
+            Instrument::Flute => Self::new(60..=96, 62..=91),
+            Instrument::Oboe => Self::new(58..=91, 62..=84),
+            Instrument::EnglishHorn => Self::new(52..=81, 55..=76),
+            Instrument::Clarinet => Self::new(50..=94, 53..=86),
+            Instrument::Bassoon => Self::new(34..=75, 38..=67),
+            Instrument::SopranoSax => Self::new(56..=88, 58..=84),
+            Instrument::AltoSax => Self::new(49..=81, 51..=77),
+            Instrument::TenorSax => Self::new(44..=76, 46..=72),
+            Instrument::BaritoneSax => Self::new(36..=69, 38..=65),
+            Instrument::Trumpet => Self::new(54..=86, 57..=81),
+            Instrument::Trombone => Self::new(40..=72, 43..=67),
+            Instrument::Tuba => Self::new(28..=58, 31..=53),
+            Instrument::FrenchHorn => Self::new(41..=77, 45..=72),
+            _ => match InstrumentFamily::of(instrument) {
+                InstrumentFamily::Piano => Self::new(21..=108, 43..=86),
+                InstrumentFamily::ChromaticPercussion => Self::new(53..=96, 60..=89),
+                InstrumentFamily::Organ => Self::new(36..=96, 43..=84),
+                InstrumentFamily::Guitar => Self::new(40..=86, 43..=76),
+                InstrumentFamily::Bass => Self::new(28..=67, 28..=55),
+                InstrumentFamily::Strings => Self::new(36..=96, 48..=84),
+                InstrumentFamily::Ensemble => Self::new(36..=96, 48..=84),
+                InstrumentFamily::Brass => Self::new(40..=84, 48..=79),
+                InstrumentFamily::Reed => Self::new(46..=86, 50..=81),
+                InstrumentFamily::Pipe => Self::new(60..=96, 62..=91),
+                InstrumentFamily::SynthLead => Self::new(36..=96, 48..=86),
+                InstrumentFamily::SynthPad => Self::new(36..=96, 43..=84),
+                InstrumentFamily::SynthEffects => Self::new(36..=96, 48..=84),
+                InstrumentFamily::Ethnic => Self::new(48..=88, 52..=81),
+                InstrumentFamily::Percussive => Self::new(48..=84, 55..=79),
+                InstrumentFamily::SoundEffects => Self::new(36..=96, 48..=84),
+            },
+        }
+    }
+
+    /// Shifts `range` by the number of octaves giving it the most overlap with the comfortable
+    /// range, then clamps it to the playable range.
+    pub fn fit(&self, range: RangeInclusive<Note>) -> RangeInclusive<Note> {
+        let shift = self.octave_shift(&range);
+        let (start, end) = (range.start().0 as i32, range.end().0 as i32);
+
+        let (playable_start, playable_end) =
+            (self.playable.start().0 as i32, self.playable.end().0 as i32);
+        let start = (start + shift).clamp(playable_start, playable_end);
+        let end = (end + shift).clamp(start, playable_end);
+
+        Note(start as u8)..=Note(end as u8)
+    }
+
+    /// Transposes `notes` together by the octave shift [`InstrumentRange::fit`] would use for
+    /// their range, keeping their contour, then moves any still outside the playable range into it
+    /// individually.
+    pub fn fit_notes<'a>(&self, notes: impl IntoIterator<Item = &'a mut Note>) {
+        let mut notes = notes.into_iter().collect::<Vec<_>>();
+        let (Some(lowest), Some(highest)) = (
+            notes.iter().map(|note| **note).min(),
+            notes.iter().map(|note| **note).max(),
+        ) else {
+            return;
+        };
+
+        let shift = self.octave_shift(&(lowest..=highest));
+        for note in notes.iter_mut() {
+            **note = self.transpose_into(Note((note.0 as i32 + shift).clamp(0, 127) as u8));
+        }
+    }
+
+    /// The shift (in semitones, by whole octaves) giving `range` the most overlap with the
+    /// comfortable range, preferring smaller shifts.
+    fn octave_shift(&self, range: &RangeInclusive<Note>) -> i32 {
+        let (start, end) = (range.start().0 as i32, range.end().0 as i32);
+        let (comfortable_start, comfortable_end) = (
+            self.comfortable.start().0 as i32,
+            self.comfortable.end().0 as i32,
+        );
+        let overlap = |shift: i32| {
+            (end + shift).min(comfortable_end) - (start + shift).max(comfortable_start)
+        };
+
+        (-4..=4)
+            .map(|octaves| octaves * 12)
+            .max_by_key(|shift| (overlap(*shift), -shift.abs()))
+            .unwrap_or(0)
+    }
+
+    /// Transposes `note` by octaves until it is within the playable range.
+    pub fn transpose_into(&self, note: Note) -> Note {
+        let mut note = note;
+        while note < *self.playable.start() {
+            note = note + Interval::P8;
+        }
+        while note > *self.playable.end() && note.0 >= 12 {
+            note = Note(note.0 - 12);
+        }
+
+        note
+    }
+}
+
+/// Whether an instrument's notes sustain or decay quickly after their attack.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InstrumentCharacter {
+    Sustained,
+    Percussive,
+}
+
+impl InstrumentCharacter {
+    pub fn of(instrument: Instrument) -> InstrumentCharacter {
+        match instrument {
+            Instrument::PizzicatoStrings
+            | Instrument::OrchestralHarp
+            | Instrument::Timpani
+            | Instrument::OrchestraHit => InstrumentCharacter::Percussive,
+            Instrument::Bagpipe | Instrument::Fiddle | Instrument::Shanai => {
+                InstrumentCharacter::Sustained
+            }
+            _ => match InstrumentFamily::of(instrument) {
+                InstrumentFamily::Piano
+                | InstrumentFamily::ChromaticPercussion
+                | InstrumentFamily::Guitar
+                | InstrumentFamily::Bass
+                | InstrumentFamily::Ethnic
+                | InstrumentFamily::Percussive => InstrumentCharacter::Percussive,
+                _ => InstrumentCharacter::Sustained,
+            },
+        }
+    }
+}
+
+/// Broad groupings of [`InstrumentFamily`]s that commonly play together.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Ensemble {
+    Orchestral,
+    Keys,
+    Band,
+    Synth,
+    World,
+    Effects,
+}
+
+impl Ensemble {
+    fn of(family: InstrumentFamily) -> Ensemble {
+        match family {
+            InstrumentFamily::Strings
+            | InstrumentFamily::Ensemble
+            | InstrumentFamily::Brass
+            | InstrumentFamily::Reed
+            | InstrumentFamily::Pipe => Ensemble::Orchestral,
+            InstrumentFamily::Piano | InstrumentFamily::ChromaticPercussion => Ensemble::Keys,
+            InstrumentFamily::Organ | InstrumentFamily::Guitar | InstrumentFamily::Bass => {
+                Ensemble::Band
+            }
+            InstrumentFamily::SynthLead
+            | InstrumentFamily::SynthPad
+            | InstrumentFamily::SynthEffects => Ensemble::Synth,
+            InstrumentFamily::Ethnic | InstrumentFamily::Percussive => Ensemble::World,
+            InstrumentFamily::SoundEffects => Ensemble::Effects,
+        }
+    }
+
+    fn affinity(&self, other: &Ensemble) -> f32 {
+        match (self, other) {
+            (a, b) if a == b => 1.0,
+            (Ensemble::Effects, _) | (_, Ensemble::Effects) => 0.1,
+            (Ensemble::Keys, _) | (_, Ensemble::Keys) => 0.8,
+            (Ensemble::Band, Ensemble::Synth) | (Ensemble::Synth, Ensemble::Band) => 0.6,
+            (Ensemble::World, Ensemble::Orchestral) | (Ensemble::Orchestral, Ensemble::World) => {
+                0.6
+            }
+            (Ensemble::Band, _) | (_, Ensemble::Band) => 0.5,
+            (Ensemble::Synth, Ensemble::Orchestral) | (Ensemble::Orchestral, Ensemble::Synth) => {
+                0.3
+            }
+            _ => 0.4,
+        }
+    }
+}
+
+/// How well instruments sound together, from their families, registers and characters.
+pub struct Compatibility;
+
+impl Compatibility {
+    /// A score in `0.0..=1.0` for playing `a` and `b` together.
+    pub fn of(a: Instrument, b: Instrument) -> f32 {
+        let affinity =
+            Ensemble::of(InstrumentFamily::of(a)).affinity(&Ensemble::of(InstrumentFamily::of(b)));
+
+        // Some shared register ties parts together, but near total overlap masks them
+        let (a_range, b_range) = (InstrumentRange::of(a), InstrumentRange::of(b));
+        let overlap = (a_range.comfortable.end().0.min(b_range.comfortable.end().0) as f32
+            - a_range
+                .comfortable
+                .start()
+                .0
+                .max(b_range.comfortable.start().0) as f32)
+            .max(0.0);
+        let smaller = (a_range.comfortable.end().0 - a_range.comfortable.start().0)
+            .min(b_range.comfortable.end().0 - b_range.comfortable.start().0)
+            .max(1) as f32;
+        let register = match overlap / smaller {
+            o if o <= 0.0 => 0.5,
+            o if o > 0.8 => 0.6,
+            _ => 1.0,
+        };
+
+        // Contrasting characters complement each other
+        let character = match (InstrumentCharacter::of(a), InstrumentCharacter::of(b)) {
+            (a, b) if a != b => 1.0,
+            (InstrumentCharacter::Sustained, _) => 0.8,
+            (InstrumentCharacter::Percussive, _) => 0.7,
+        };
+
+        affinity * register * character
+    }
+
+    /// Chooses from `candidates`, weighted by compatibility with every instrument in `chosen`.
+    pub fn choose(
+        candidates: &[Instrument],
+        chosen: &[Instrument],
+        rng: &mut impl Rng,
+    ) -> Option<Instrument> {
+        candidates
+            .choose_weighted(rng, |candidate| {
+                chosen
+                    .iter()
+                    .map(|other| Self::of(*candidate, *other))
+                    .product::<f32>()
+                    .max(0.001)
+            })
+            .ok()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notes(notes: &[u8]) -> Vec<Note> {
+        notes.iter().map(|n| Note(*n)).collect()
+    }
+
+    #[test]
+    fn fit_shifts_range_by_octaves() {
+        let violin = InstrumentRange::of(Instrument::Violin);
+
+        assert_eq!(violin.fit(Note(36)..=Note(48)), Note(60)..=Note(72));
+        assert_eq!(violin.fit(Note(100)..=Note(120)), Note(64)..=Note(84));
+    }
+
+    #[test]
+    fn fit_clamps_to_playable_range() {
+        let contrabass = InstrumentRange::of(Instrument::Contrabass);
+
+        assert_eq!(contrabass.fit(Note(20)..=Note(80)), Note(28)..=Note(67));
+    }
+
+    #[test]
+    fn fit_notes_keeps_contour() {
+        let violin = InstrumentRange::of(Instrument::Violin);
+
+        let mut run = notes(&[36, 38, 40, 43, 48]);
+        violin.fit_notes(run.iter_mut());
+        assert_eq!(run, notes(&[60, 62, 64, 67, 72]));
+    }
+
+    #[test]
+    fn fit_notes_folds_remaining_outliers() {
+        let violin = InstrumentRange::of(Instrument::Violin);
+
+        let mut run = notes(&[50, 62, 74, 86, 98, 110]);
+        violin.fit_notes(run.iter_mut());
+        assert_eq!(run, notes(&[62, 62, 74, 86, 98, 98]));
+    }
+}
@@ -1,25 +1,36 @@
+use crate::automation::{Automation, AutomationLane, AutomationShape};
+use crate::bass::{BassLine, Riff};
 use crate::chord_progression::ChordMarkers;
+use crate::drums::{
+    Boundary, DrumDynamics, DrumGrid, DrumLayer, DrumNote, DrumRole, DrumVoices, GrooveTemplate,
+};
+use crate::dynamics::VelocityShaper;
+use crate::feel::{Humanize, Polyrhythm, Swing};
 use crate::melody;
-use crate::melody::{Melody, MelodyDirective};
+use crate::melody::{Melody, MelodyDirective, Ornamentation};
+use crate::mixing::{Mix, PartRole};
+use crate::orchestration::{InstrumentCharacter, InstrumentFamily, InstrumentRange};
 use crate::structure::{PhraseDivider, Section};
-use crate::util::{generate_sawtooth_fn, merge_sawtooth_fns};
+use crate::style::Style;
+use crate::util::Modulation;
 use rand::distributions::{Distribution, WeightedIndex};
 use rand::prelude::{IteratorRandom, SliceRandom};
 use rand::Rng;
+use redact_composer::elements::PlayNote;
+use redact_composer::error::RendererError;
 use redact_composer::midi::elements::DrumKit;
-use redact_composer::midi::gm::{
-    elements::{DrumHit, Instrument},
-    DrumHitType,
-};
+use redact_composer::midi::gm::elements::{DrumHit, Instrument};
 use redact_composer::musical::elements::{Chord, Key, TimeSignature};
 use redact_composer::musical::rhythm::Rhythm;
-use redact_composer::musical::{Interval, NoteIterator};
+use redact_composer::musical::{Interval, Note, NoteIterator, PitchClassCollection};
+use redact_composer::render::context::CompositionContext;
 use redact_composer::render::context::TimingRelation::{
     BeginningWithin, During, Overlapping, Within,
 };
 use redact_composer::render::{AdhocRenderer, RenderEngine, RendererGroup};
+use redact_composer::timing::elements::Tempo;
 use redact_composer::util::IntoSegment;
-use redact_composer::{Element, Renderer};
+use redact_composer::{Element, Renderer, Segment, SegmentRef};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::iter::once;
@@ -29,101 +40,413 @@ pub fn renderers() -> RenderEngine {
         + melody::renderers()
         + BassPart::renderer()
         + MelodyPart::renderer()
+        + HarmonyPart::renderer()
         + DrumPart::renderer()
 }
 
+/// How a [`BassPart`] builds its line.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum BassStyle {
+    /// Runs toward chord roots, fourths and fifths through [`MelodyDirective`]s.
+    Directed,
+    /// One note per beat, approaching each chord change.
+    Walking,
+    /// Attacks locked to the kick drum hits of the [`DrumPart`] over the same span.
+    KickLocked,
+    /// A one or two bar [`Riff`] repeated across the [`Section`], following each chord.
+    Riff,
+}
+
+impl BassStyle {
+    /// Chooses one of `allowed`, or any style if none are.
+    pub fn random(allowed: &[BassStyle], rng: &mut impl Rng) -> BassStyle {
+        Style::choose_from(
+            allowed,
+            &[
+                BassStyle::Directed,
+                BassStyle::Directed,
+                BassStyle::Walking,
+                BassStyle::KickLocked,
+                BassStyle::Riff,
+            ],
+            rng,
+        )
+        .unwrap_or(BassStyle::Directed)
+    }
+}
+
 #[non_exhaustive]
 #[derive(Element, Serialize, Deserialize, Debug)]
 pub struct BassPart {
     pub instrument: Instrument,
+    pub style: BassStyle,
 }
 
 impl BassPart {
     #[allow(clippy::new_ret_no_self)]
     pub fn new(instrument: Instrument) -> impl Element {
-        Melody::new(BassPart { instrument })
+        Melody::new(BassPart {
+            instrument,
+            style: BassStyle::Directed,
+        })
+    }
+
+    /// Creates a [`BassPart`] which produces its notes directly rather than through a [`Melody`].
+    pub fn styled(instrument: Instrument, style: BassStyle) -> Self {
+        BassPart { instrument, style }
     }
 
     fn renderer() -> impl Renderer<Element = Self> {
         RendererGroup::new()
             + AdhocRenderer::<Self>::new(|bass_part, _| {
-                Ok(vec![bass_part.element.instrument.over(bass_part)])
+                // Only the melody line of directed parts reads its humanization back
+                let humanize = matches!(bass_part.element.style, BassStyle::Directed)
+                    .then(|| Humanize::bass().over(bass_part));
+
+                Ok(vec![
+                    bass_part.element.instrument.over(bass_part),
+                    Mix::of(PartRole::Bass).over(bass_part),
+                ]
+                .into_iter()
+                .chain(humanize)
+                .collect())
             })
-            + AdhocRenderer::<Self>::new(|bass_part, ctx| {
-                let mut rng = ctx.rng();
-                let key = ctx
-                    .find::<Key>()
+            + AdhocRenderer::<Self>::new(|bass_part, ctx| match bass_part.element.style {
+                BassStyle::Directed => Self::directives(bass_part, ctx),
+                BassStyle::Walking | BassStyle::KickLocked | BassStyle::Riff => {
+                    Self::styled_notes(bass_part, ctx)
+                }
+            })
+    }
+
+    fn directives(
+        bass_part: SegmentRef<Self>,
+        ctx: &CompositionContext,
+    ) -> Result<Vec<Segment>, RendererError> {
+        let mut rng = ctx.rng();
+        let key = ctx
+            .find::<Key>()
+            .with_timing(During, bass_part)
+            .require()?
+            .element;
+        let dividers = ctx
+            .find::<PhraseDivider>()
+            .with_timing(Within, bass_part)
+            .require_all()?;
+        let chords = ctx
+            .find::<Chord>()
+            .within::<ChordMarkers>()
+            .with_timing(Overlapping, bass_part)
+            .require_all()?;
+
+        let note_range = InstrumentRange::of(bass_part.element.instrument)
+            .fit(key.root().in_octave(2)..=(key.root().in_octave(3) + Interval(7)));
+
+        let directives = chords
+            .iter()
+            .flat_map(|ch| {
+                let run_to_note = [Interval::P1, Interval::P4, Interval::P5]
+                    .into_iter()
+                    .map(|i| ch.element.root() + i)
+                    .filter(|pc| key.contains(pc))
+                    .flat_map(|pc| pc.iter_notes_in_range(note_range.clone()))
+                    .choose(&mut rng)
+                    .unwrap();
+                let key_note = *ch
+                    .element
+                    .root()
+                    .notes_in_range(note_range.clone())
+                    .choose(&mut rng)
+                    .unwrap();
+                let preceding_div = dividers
+                    .iter()
+                    .find(|div| div.timing.end == ch.timing.start);
+                let current_div = dividers
+                    .iter()
+                    .find(|div| div.timing.contains(&ch.timing.start));
+
+                let run_to_directive = preceding_div
+                    .map(|preceding_div| Melody::run_to(run_to_note).over(preceding_div));
+
+                let key_note_directive =
+                    current_div.map(|current_div| Melody::key_note(key_note).over(current_div));
+
+                once(run_to_directive)
+                    .chain(once(key_note_directive))
+                    .flatten()
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        Ok(directives)
+    }
+
+    /// Renders the notes of non-[`BassStyle::Directed`] styles, applying the same dynamics,
+    /// articulation, swing and humanization a [`Melody`] would.
+    fn styled_notes(
+        bass_part: SegmentRef<Self>,
+        ctx: &CompositionContext,
+    ) -> Result<Vec<Segment>, RendererError> {
+        let mut rng = ctx.rng();
+        let key = ctx
+            .find::<Key>()
+            .with_timing(During, bass_part)
+            .require()?
+            .element;
+        let ts = ctx
+            .find::<TimeSignature>()
+            .with_timing(During, bass_part)
+            .require()?;
+        let dividers = ctx
+            .find::<PhraseDivider>()
+            .with_timing(Within, bass_part)
+            .require_all()?;
+        let chords = ctx
+            .find::<Chord>()
+            .within::<ChordMarkers>()
+            .with_timing(Overlapping, bass_part)
+            .require_all()?;
+
+        let timing = bass_part.timing.start..bass_part.timing.end;
+        let bar_starts = (ts.timing.start..bass_part.timing.end)
+            .step_by(ts.element.bar() as usize)
+            .filter(|start| timing.contains(start));
+        let bass_line = BassLine {
+            key,
+            chords: chords
+                .iter()
+                .map(|ch| (ch.timing.start..ch.timing.end, ch.element))
+                .collect(),
+            anchors: dividers
+                .iter()
+                .map(|div| div.timing.start)
+                .chain(bar_starts)
+                .collect(),
+            range: InstrumentRange::of(bass_part.element.instrument)
+                .fit(key.root().in_octave(2)..=(key.root().in_octave(3) + Interval(7))),
+            beat: ts.element.beat(),
+        };
+
+        let velocities = VelocityShaper::from_context(ctx, timing.clone())?;
+        let swing = Swing::grid(ctx, timing.clone())?;
+        let humanizer = Humanize::bass().humanizer(ctx, timing.clone())?;
+        let mut humanize_rng = ctx.rng_with_seed("humanize");
+
+        // Kick positions already carry the drums' swing and humanization, so kick-locked lines
+        // are planned on the straight grid the kicks came from, then attack exactly where each
+        // kick was placed to keep both parts locked together.
+        let (line, placed_kicks) = match bass_part.element.style {
+            BassStyle::Walking => (bass_line.walking(timing, &mut rng), None),
+            BassStyle::Riff => {
+                // The riff is aligned to, and shared across, the whole section
+                let section = ctx
+                    .find::<Section>()
                     .with_timing(During, bass_part)
-                    .require()?
-                    .element;
-                let dividers = ctx
+                    .require()?;
+                let mut riff_rng = ctx.rng_with_seed("riff");
+                let bars = riff_rng.gen_range(1..=2);
+                let riff_len = ts.element.bars(bars);
+                let accents = ctx
                     .find::<PhraseDivider>()
-                    .with_timing(Within, bass_part)
-                    .require_all()?;
-                let chords = ctx
-                    .find::<Chord>()
-                    .within::<ChordMarkers>()
-                    .with_timing(Overlapping, bass_part)
-                    .require_all()?;
+                    .with_timing(Within, section)
+                    .get_all()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|div| div.timing.start - section.timing.start)
+                    .filter(|start| *start < riff_len)
+                    .collect::<Vec<_>>();
+                let riff = Riff::random(
+                    bars,
+                    ts.element.beats_per_bar,
+                    ts.element.beat(),
+                    &accents,
+                    &mut riff_rng,
+                );
 
-                let directives = chords
+                (bass_line.riff(&riff, section.timing.start, timing), None)
+            }
+            BassStyle::KickLocked => {
+                let beat = ts.element.beat();
+                let kicks = ctx
+                    .find::<DrumHit>()
+                    .within::<DrumPart>()
+                    .with_timing(Overlapping, bass_part)
+                    .matching(|hit| DrumRole::of(hit.hit) == Some(DrumRole::Kick))
+                    .require_all()?
                     .iter()
-                    .flat_map(|ch| {
-                        let note_range =
-                            key.root().in_octave(2)..(key.root().in_octave(3) + Interval(8));
-                        let run_to_note = [Interval::P1, Interval::P4, Interval::P5]
-                            .into_iter()
-                            .map(|i| ch.element.root() + i)
-                            .filter(|pc| key.contains(pc))
-                            .flat_map(|pc| pc.iter_notes_in_range(note_range.clone()))
-                            .choose(&mut rng)
-                            .unwrap();
-                        let key_note = *ch
-                            .element
-                            .root()
-                            .notes_in_range(note_range.clone())
-                            .choose(&mut rng)
-                            .unwrap();
-                        let preceding_div = dividers
-                            .iter()
-                            .find(|div| div.timing.end == ch.timing.start);
-                        let current_div = dividers
-                            .iter()
-                            .find(|div| div.timing.contains(&ch.timing.start));
+                    .map(|hit| {
+                        let start = hit.timing.start;
 
-                        let run_to_directive = preceding_div
-                            .map(|preceding_div| Melody::run_to(run_to_note).over(preceding_div));
+                        (swing.straighten(start, &[beat / 4, beat / 3]), start)
+                    })
+                    .collect::<HashMap<_, _>>();
+                let straight_kicks = kicks.keys().copied().collect::<Vec<_>>();
 
-                        let key_note_directive = current_div
-                            .map(|current_div| Melody::key_note(key_note).over(current_div));
+                (
+                    bass_line.kick_locked(&straight_kicks, timing, &mut rng),
+                    Some(kicks),
+                )
+            }
+            BassStyle::Directed => (vec![], None),
+        };
 
-                        once(run_to_directive)
-                            .chain(once(key_note_directive))
-                            .flatten()
-                            .collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>();
+        let notes = line
+            .into_iter()
+            .map(|(note, note_timing)| {
+                let velocity = velocities.velocity(note_timing.start, &mut rng);
 
-                Ok(directives)
+                (note, note_timing, velocity)
             })
+            .collect::<Vec<_>>();
+        let tempo = ctx
+            .find::<Tempo>()
+            .with_timing(During, bass_part)
+            .get()
+            .map(|tempo| tempo.element);
+        let articulated = melody::articulate(
+            &notes,
+            dividers
+                .iter()
+                .map(|div| div.timing.start..div.timing.end)
+                .collect(),
+            bass_part.timing.start..bass_part.timing.end,
+            tempo,
+            Some(InstrumentFamily::of(bass_part.element.instrument)),
+            ts.element,
+            &mut ctx.rng_with_seed("articulation"),
+        );
+
+        Ok(notes
+            .into_iter()
+            .zip(articulated)
+            .map(|((note, _, _), (_, note_timing, velocity))| {
+                let (note_timing, velocity) = match &placed_kicks {
+                    Some(kicks) => {
+                        let place = |time: i32| {
+                            kicks
+                                .get(&time)
+                                .copied()
+                                .unwrap_or_else(|| swing.apply(time))
+                        };
+
+                        (place(note_timing.start)..place(note_timing.end), velocity)
+                    }
+                    None => humanizer.apply(
+                        swing.apply_range(note_timing),
+                        velocity,
+                        None,
+                        &mut humanize_rng,
+                    ),
+                };
+
+                note.play(velocity).over(note_timing)
+            })
+            .collect())
     }
 }
 
 #[derive(Element, Serialize, Deserialize, Debug)]
 pub struct MelodyPart {
     instrument: Instrument,
+    role: PartRole,
 }
 
 impl MelodyPart {
     #[allow(clippy::new_ret_no_self)]
-    pub fn new(instrument: Instrument) -> impl Element {
-        Melody::new(MelodyPart { instrument })
+    pub fn new(instrument: Instrument, role: PartRole) -> impl Element {
+        Melody::new(MelodyPart { instrument, role })
+    }
+
+    /// Creates a [`MelodyPart`] whose notes will be followed by any [`HarmonyPart`] over the same
+    /// timing.
+    pub fn harmonized(instrument: Instrument, role: PartRole) -> impl Element {
+        Harmonized::new(Self::new(instrument, role))
     }
 
     pub fn renderer() -> impl Renderer<Element = Self> {
         RendererGroup::new()
             + AdhocRenderer::<Self>::new(|melody_part, _| {
-                Ok(vec![melody_part.element.instrument.over(melody_part)])
+                Ok(vec![
+                    melody_part.element.instrument.over(melody_part),
+                    Mix::of(melody_part.element.role).over(melody_part),
+                    Ornamentation::default().over(melody_part),
+                    Humanize::lead().over(melody_part),
+                ])
+            })
+            + AdhocRenderer::<Self>::new(|melody_part, ctx| {
+                let mut rng = ctx.rng_with_seed("automation");
+                let instrument = melody_part.element.instrument;
+                let ts = ctx
+                    .find::<TimeSignature>()
+                    .with_timing(During, melody_part)
+                    .require()?
+                    .element;
+                let section = ctx
+                    .find::<Section>()
+                    .with_timing(During, melody_part)
+                    .require()?;
+                let dividers = ctx
+                    .find::<PhraseDivider>()
+                    .with_timing(BeginningWithin, melody_part)
+                    .require_all()?;
+
+                // Sustained instruments swell through some of their phrases, with the others
+                // returning to full expression
+                let sustained =
+                    InstrumentCharacter::of(instrument) == InstrumentCharacter::Sustained;
+                let expression = dividers
+                    .iter()
+                    .map(|div| {
+                        if sustained && div.timing.len() >= ts.beat() && rng.gen_bool(0.5) {
+                            Automation {
+                                lane: AutomationLane::Expression,
+                                shape: AutomationShape::Swell,
+                                from: 90.0,
+                                to: 127.0,
+                            }
+                            .over(div)
+                        } else {
+                            Automation::reset(AutomationLane::Expression)
+                                .over(div.timing.start..div.timing.start + 1)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                // Synths brighten through the build-up at the end of each section
+                let build_up = (section.timing.end - ts.bars(2)).max(section.timing.start);
+                let brightness = Some(InstrumentFamily::of(instrument))
+                    .filter(|family| {
+                        matches!(
+                            family,
+                            InstrumentFamily::SynthLead
+                                | InstrumentFamily::SynthPad
+                                | InstrumentFamily::SynthEffects
+                        )
+                    })
+                    .map(|_| {
+                        let reset = Some(melody_part.timing.start)
+                            .filter(|start| *start < build_up)
+                            .map(|start| {
+                                Automation::reset(AutomationLane::Brightness)
+                                    .over(start..(start + ts.beat()).min(build_up))
+                            });
+                        let sweep = Some(melody_part.timing.start.max(build_up))
+                            .filter(|start| *start < melody_part.timing.end)
+                            .map(|start| {
+                                Automation {
+                                    lane: AutomationLane::Brightness,
+                                    shape: AutomationShape::Ramp,
+                                    from: 40.0,
+                                    to: 110.0,
+                                }
+                                .over(start..melody_part.timing.end)
+                            });
+
+                        reset.into_iter().chain(sweep).collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+
+                Ok(expression.into_iter().chain(brightness).collect::<Vec<_>>())
             })
             + AdhocRenderer::<Self>::new(|melody_part, ctx| {
                 let mut rng = ctx.rng();
@@ -154,11 +477,11 @@ impl MelodyPart {
 
                 let period = melody_part.timing.len() / rng.gen_range(1..=8);
                 let offset = rng.gen_range(0..period);
-                let msawtooth = generate_sawtooth_fn(period as f32, offset as f32);
+                let msawtooth = Modulation::sawtooth(period as f32, offset as f32);
                 let period = section.timing.len() / rng.gen_range(1..=8);
                 let offset = rng.gen_range(0..period);
-                let ssawtooth = generate_sawtooth_fn(period as f32, offset as f32);
-                let combsaw = merge_sawtooth_fns(msawtooth, ssawtooth, 1.0);
+                let ssawtooth = Modulation::sawtooth(period as f32, offset as f32);
+                let combsaw = msawtooth.merge(ssawtooth, 1.0);
 
                 let note_starts = dividers
                     .iter()
@@ -168,10 +491,12 @@ impl MelodyPart {
                             .find(|ch| ch.timing.contains(&div.timing.start))
                             .map(|segment| segment.element)?;
 
-                        let note_choices = chord
-                            .iter_notes_in_range(
-                                chord.root().in_octave(3)..=chord.root().in_octave(5),
-                            )
+                        let note_range = InstrumentRange::of(melody_part.element.instrument)
+                            .fit(chord.root().in_octave(3)..=chord.root().in_octave(5));
+                        let chord_notes = chord.iter_notes_in_range(note_range).collect::<Vec<_>>();
+                        let note_choices = chord_notes
+                            .iter()
+                            .copied()
                             .filter(|n| {
                                 // Randomly remove note choices with probability corresponding to the number
                                 // of other parts playing the same pitch
@@ -189,8 +514,15 @@ impl MelodyPart {
                                 rng.gen_bool(0.5_f64.powf(overlaps as f64))
                             })
                             .collect::<Vec<_>>();
-                        let start = combsaw((div.timing.start - melody_part.timing.start) as f32);
-                        let start_note = note_choices[(start * note_choices.len() as f32) as usize];
+                        let note_choices = if note_choices.is_empty() {
+                            chord_notes
+                        } else {
+                            note_choices
+                        };
+                        let start =
+                            combsaw.value((div.timing.start - melody_part.timing.start) as f32);
+                        let idx = (start * note_choices.len() as f32) as usize;
+                        let start_note = *note_choices.get(idx).or(note_choices.last())?;
                         Some((start_note, div.timing.start))
                     })
                     .collect::<Vec<_>>();
@@ -209,6 +541,181 @@ impl MelodyPart {
                 Ok(key_notes
                     .into_iter()
                     .chain(run_to_notes)
+                    .chain(once(combsaw.over(melody_part)))
+                    .collect::<Vec<_>>())
+            })
+    }
+}
+
+/// Marks a melody as the lead voice for a [`HarmonyPart`].
+#[derive(Element, Serialize, Deserialize, Debug)]
+#[element(wrapped_element = "Some(&*self.melody)")]
+#[element(wrapped_element_doc = "The melody being harmonized.")]
+pub struct Harmonized {
+    melody: Box<dyn Element>,
+}
+
+impl Harmonized {
+    pub fn new(melody: impl Element) -> Self {
+        Self {
+            melody: Box::new(melody),
+        }
+    }
+}
+
+/// Diatonic intervals a [`HarmonyPart`] can follow its lead by.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Harmony {
+    ThirdAbove,
+    ThirdBelow,
+    SixthAbove,
+    SixthBelow,
+}
+
+impl Harmony {
+    pub fn values() -> Vec<Harmony> {
+        vec![
+            Harmony::ThirdAbove,
+            Harmony::ThirdBelow,
+            Harmony::SixthAbove,
+            Harmony::SixthBelow,
+        ]
+    }
+
+    /// Number of scale steps between the lead and harmony notes.
+    fn scale_steps(&self) -> i32 {
+        match self {
+            Harmony::ThirdAbove => 2,
+            Harmony::ThirdBelow => -2,
+            Harmony::SixthAbove => 5,
+            Harmony::SixthBelow => -5,
+        }
+    }
+
+    /// Finds the harmony note for `lead`, moving diatonically within `key`, or `None` if it falls
+    /// outside the scale's range.
+    pub fn harmonize(&self, lead: Note, key: &Key, chord: &Chord) -> Option<Note> {
+        let scale = key.notes_in_range(key.root().in_octave(1)..key.root().in_octave(8));
+        let parallel = match scale.iter().position(|n| *n == lead) {
+            Some(idx) => {
+                let idx = usize::try_from(idx as i32 + self.scale_steps()).ok()?;
+                Some(*scale.get(idx)?)
+            }
+            None => None,
+        };
+
+        let chord_pitches = chord.pitch_classes();
+        let clashes = |note: &Note| {
+            !chord_pitches.contains(&note.pitch_class())
+                && chord_pitches.iter().any(|pc| {
+                    note.pitch_class().interval_to(pc) == Interval::m2
+                        || note.pitch_class().interval_from(pc) == Interval::m2
+                })
+        };
+        // Thirds, fourths, fifths, sixths and octaves
+        let consonant = |note: &Note| {
+            matches!(
+                (note.0 as i32 - lead.0 as i32).rem_euclid(12),
+                0 | 3 | 4 | 5 | 7 | 8 | 9
+            )
+        };
+
+        match parallel {
+            Some(note) if !clashes(&note) => Some(note),
+            _ => {
+                let target = parallel.unwrap_or(lead);
+                let (low, high) = if self.scale_steps() > 0 {
+                    (target, lead + Interval::P8 + Interval::P8)
+                } else {
+                    (
+                        lead.pitch_class().in_octave(lead.octave() - 2),
+                        Note(target.0 + 1),
+                    )
+                };
+
+                chord
+                    .iter_notes_in_range(low..high)
+                    .filter(|n| *n != lead && consonant(n))
+                    .min_by_key(|n| (n.0 as i32 - target.0 as i32).abs())
+            }
+        }
+    }
+}
+
+/// A part which follows the notes of a [`Harmonized`] melody over the same timing, at a diatonic
+/// [`Harmony`] interval.
+#[derive(Element, Serialize, Deserialize, Debug)]
+pub struct HarmonyPart {
+    instrument: Instrument,
+    harmony: Harmony,
+}
+
+impl HarmonyPart {
+    pub fn new(instrument: Instrument, harmony: Harmony) -> Self {
+        Self {
+            instrument,
+            harmony,
+        }
+    }
+
+    pub fn renderer() -> impl Renderer<Element = Self> {
+        RendererGroup::new()
+            + AdhocRenderer::<Self>::new(|harmony_part, _| {
+                Ok(vec![
+                    harmony_part.element.instrument.over(harmony_part),
+                    Mix::of(PartRole::Harmony).over(harmony_part),
+                ])
+            })
+            + AdhocRenderer::<Self>::new(|harmony_part, ctx| {
+                let range = InstrumentRange::of(harmony_part.element.instrument);
+                let key = ctx
+                    .find::<Key>()
+                    .with_timing(During, harmony_part)
+                    .require()?
+                    .element;
+                let chords = ctx
+                    .find::<Chord>()
+                    .within::<ChordMarkers>()
+                    .with_timing(Overlapping, harmony_part)
+                    .require_all()?;
+                // Once rendered, a lead without directives stays silent, leaving nothing to follow
+                ctx.find::<Mix>()
+                    .within::<Harmonized>()
+                    .with_timing(During, harmony_part)
+                    .require()?;
+                let directives = ctx
+                    .find::<MelodyDirective>()
+                    .within::<Harmonized>()
+                    .with_timing(Within, harmony_part)
+                    .get_all()
+                    .unwrap_or_default();
+                if directives.is_empty() {
+                    return Ok(vec![]);
+                }
+                let lead_notes = ctx
+                    .find::<PlayNote>()
+                    .within::<Harmonized>()
+                    .with_timing(Within, harmony_part)
+                    .require_all()?;
+
+                Ok(lead_notes
+                    .iter()
+                    .flat_map(|lead| {
+                        let chord = chords
+                            .iter()
+                            .find(|ch| ch.timing.contains(&lead.timing.start))?
+                            .element;
+                        let note = harmony_part
+                            .element
+                            .harmony
+                            .harmonize(lead.element.note, key, chord)
+                            .map(|note| range.transpose_into(note))?;
+
+                        Some(
+                            note.play(lead.element.velocity.saturating_sub(10))
+                                .over(lead.timing),
+                        )
+                    })
                     .collect::<Vec<_>>())
             })
     }
@@ -227,100 +734,428 @@ impl DrumPart {
     pub fn renderer() -> impl Renderer<Element = Self> {
         RendererGroup::new()
             + AdhocRenderer::<Self>::new(|drum_part, _| {
-                Ok(vec![drum_part.element.kit.over(drum_part)])
+                Ok(vec![
+                    drum_part.element.kit.over(drum_part),
+                    Mix::of(PartRole::Drums).over(drum_part),
+                ])
             })
             + AdhocRenderer::<Self>::new(|drum_part, context| {
                 let mut rng = context.rng();
                 let ts = context
                     .find::<TimeSignature>()
                     .with_timing(During, drum_part)
-                    .require()?
-                    .element;
+                    .require()?;
                 let dividers = context
                     .find::<PhraseDivider>()
                     .with_timing(BeginningWithin, drum_part)
                     .require_all()?;
+                let bpm = context
+                    .find::<Tempo>()
+                    .with_timing(During, drum_part)
+                    .get()
+                    .map(|tempo| tempo.element.bpm() as f32)
+                    .unwrap_or(120.0);
+                let velocities = VelocityShaper::from_context(
+                    context,
+                    drum_part.timing.start..drum_part.timing.end,
+                )?;
+                let swing = Swing::grid(context, drum_part.timing.start..drum_part.timing.end)?;
+                let humanizer = Humanize::drums()
+                    .humanizer(context, drum_part.timing.start..drum_part.timing.end)?;
+                let mut humanize_rng = context.rng_with_seed("humanize");
 
-                let mut phrase_lengths = dividers
+                // Shared by the drum parts of a section so they play the same kit pieces and groove
+                let voices = DrumVoices::random(&mut context.rng_with_seed("drum_voices"));
+                let style = Style::during(context, drum_part.timing.start..drum_part.timing.end);
+                let groove = GrooveTemplate::choose(
+                    ts.element.beats_per_bar,
+                    bpm,
+                    &style.grooves,
+                    &mut context.rng_with_seed("groove"),
+                );
+
+                let phrases = dividers
                     .iter()
-                    .map(|div| div.timing.len())
+                    .map(|div| div.timing.start..div.timing.end)
                     .collect::<Vec<_>>();
-                phrase_lengths.sort();
-                phrase_lengths.dedup();
+                let steps_per_beat = match &groove {
+                    Some(groove) if groove.steps_per_beat % 3 == 0 => 3,
+                    Some(_) => 4,
+                    None => swing.steps_per_beat(ts.element.beat()),
+                };
 
-                let hit_probabilities = [
-                    (DrumHitType::AcousticBassDrum, 1),
-                    (DrumHitType::AcousticSnare, 1),
-                    (DrumHitType::ClosedHiHat, 8),
-                    (DrumHitType::PedalHiHat, 4),
-                ];
-                let dist = WeightedIndex::new(hit_probabilities.iter().map(|i| i.1)).unwrap();
+                let mut planned_hits = if let Some(groove) = groove {
+                    groove.hits(
+                        &voices,
+                        ts.timing.start,
+                        ts.element.beat(),
+                        &phrases,
+                        &mut rng,
+                    )
+                } else {
+                    Self::random_hits(drum_part, &dividers, ts.element, &voices, context)
+                };
 
-                let rest_probability = rng.gen_range(0.3..=0.9);
+                context
+                    .find::<DrumDynamics>()
+                    .with_timing(During, drum_part)
+                    .get()
+                    .map(|dynamics| *dynamics.element)
+                    .unwrap_or_default()
+                    .layer(
+                        &mut planned_hits,
+                        &voices,
+                        &DrumGrid {
+                            bar_origin: ts.timing.start,
+                            beat: ts.element.beat(),
+                            steps_per_beat,
+                            phrases: &phrases,
+                        },
+                        &mut context.rng_with_seed("drum_dynamics"),
+                    );
 
-                let drum_beats = phrase_lengths
-                    .iter()
-                    .map(|l| {
-                        let rhythm_precision = ts.quarter_beat();
-                        let mut beat_rhythm = Rhythm::random(
-                            l - rhythm_precision,
-                            ts,
-                            |n| {
-                                (((n - rhythm_precision) as f32).clamp(0.0, ts.beat() as f32)
-                                    / ts.beat() as f32)
-                                    .powf(0.1)
-                            },
-                            |_| rest_probability,
-                            &mut rng,
-                        );
-                        beat_rhythm = Rhythm::from([rhythm_precision]) + beat_rhythm;
+                // A cross-rhythm pulse played by a colour voice, or the secondary timekeeper
+                if let Some(polyrhythm) = context
+                    .find::<Polyrhythm>()
+                    .with_timing(During, drum_part)
+                    .get()
+                    .filter(|polyrhythm| polyrhythm.element.drums)
+                {
+                    let pulse_hit = voices
+                        .colours
+                        .first()
+                        .copied()
+                        .unwrap_or(voices.timekeepers[1]);
+                    let pulse_len = polyrhythm.element.pulse_len(ts.element);
+                    for phrase in phrases.iter() {
+                        for pulse in polyrhythm.element.pulses(phrase.clone(), ts.element) {
+                            planned_hits.retain(|h| h.timing.start != pulse || h.hit != pulse_hit);
+                            planned_hits.push(DrumNote {
+                                hit: pulse_hit,
+                                timing: pulse..(pulse + pulse_len).min(phrase.end),
+                                phrase_end: phrase.end,
+                                velocity_offset: 6,
+                                layer: DrumLayer::Groove,
+                            });
+                        }
+                    }
+                }
 
-                        let hits = beat_rhythm
-                            .iter()
-                            .map(|_| hit_probabilities[dist.sample(&mut rng)].0)
-                            .collect::<Vec<_>>();
+                // Replace the groove leading into phrase and section boundaries with fills. Each
+                // fill is followed by a crash on the next downbeat, played by the part holding it,
+                // so every part of the section plans fills from the same seed per phrase. The
+                // start of the next section is already accented below.
+                let section = context
+                    .find::<Section>()
+                    .with_timing(During, drum_part)
+                    .require()?;
+                let section_phrases = context
+                    .find::<PhraseDivider>()
+                    .with_timing(Within, section)
+                    .get_all()
+                    .unwrap_or_default();
+                let bar = ts.element.bar();
+                let plan_fill = |div: &SegmentRef<PhraseDivider>| {
+                    let boundary = if div.timing.end == section.timing.end {
+                        Boundary::Section
+                    } else if (div.timing.end - ts.timing.start) % bar == 0 {
+                        Boundary::Bar
+                    } else {
+                        Boundary::Phrase
+                    };
 
-                        (*l, (beat_rhythm, hits))
-                    })
-                    .collect::<HashMap<_, _>>();
+                    boundary.fill(
+                        &voices,
+                        div.timing.start..div.timing.end,
+                        ts.element.beat(),
+                        bar,
+                        &mut context.rng_with_seed(("fills", div.timing.start)),
+                    )
+                };
+                for div in dividers.iter() {
+                    let Some(fill) = plan_fill(div) else {
+                        continue;
+                    };
+
+                    let fill_start = fill
+                        .first()
+                        .map(|n| n.timing.start)
+                        .unwrap_or(div.timing.end);
+                    planned_hits.retain(|planned| {
+                        !(fill_start..div.timing.end).contains(&planned.timing.start)
+                            || (planned.hit == voices.kick
+                                && (planned.timing.start - ts.timing.start) % bar == 0)
+                    });
+                    planned_hits.extend(fill);
+                }
 
-                Ok(dividers
+                let mut crashes = section_phrases
                     .iter()
-                    .enumerate()
-                    .flat_map(|(idx, div)| {
-                        if let Some((rhythm, hits)) = drum_beats.get(&div.timing.len()) {
-                            let mut alt_hit_rng = context.rng_with_seed(idx);
-                            let mut modified_rhythm = rhythm.clone();
-                            let mut modified_hits = hits.clone();
-                            let forced_hit =
-                                if (div.timing.start - drum_part.timing.start) % ts.bar() == 0 {
-                                    DrumHitType::AcousticBassDrum
-                                } else {
-                                    [DrumHitType::AcousticSnare, DrumHitType::AcousticBassDrum]
-                                        [alt_hit_rng.gen_range(0..=1)]
-                                };
-                            if hits.first().is_some() {
-                                modified_hits[0] = forced_hit;
-                                modified_rhythm.0[0].is_rest = false;
-                            };
-
-                            modified_rhythm
-                                .iter_over(div)
-                                .filter(|div| !div.is_rest)
-                                .zip(modified_hits.iter().cycle())
-                                .map(|(div, drum_hit)| {
-                                    DrumHit {
-                                        hit: *drum_hit,
-                                        velocity: rng.gen_range(90..110),
-                                    }
-                                    .over(div)
-                                })
-                                .collect()
-                        } else {
-                            vec![]
+                    .filter(|div| div.timing.end <= drum_part.timing.end)
+                    .filter(|div| plan_fill(div).is_some())
+                    .map(|div| {
+                        ts.timing.start
+                            + (div.timing.end - ts.timing.start + bar - 1).div_euclid(bar) * bar
+                    })
+                    .filter(|crash| drum_part.timing.contains(crash) && *crash < section.timing.end)
+                    .collect::<Vec<_>>();
+                crashes.sort();
+                crashes.dedup();
+                for crash in crashes {
+                    let phrase_end = section_phrases
+                        .iter()
+                        .find(|phrase| phrase.timing.contains(&crash))
+                        .map(|phrase| phrase.timing.end)
+                        .unwrap_or(section.timing.end)
+                        .min(drum_part.timing.end);
+                    planned_hits.push(DrumNote {
+                        hit: voices.accent,
+                        timing: crash..(crash + ts.element.beat()).min(phrase_end),
+                        phrase_end,
+                        velocity_offset: 10,
+                        layer: DrumLayer::Accent,
+                    });
+                }
+
+                let hits = planned_hits
+                    .into_iter()
+                    .map(|planned| {
+                        let velocity = planned.layer.velocity(
+                            velocities.velocity(planned.timing.start, &mut rng) as i32
+                                + planned.velocity_offset,
+                        );
+                        let (timing, velocity) = humanizer.apply(
+                            swing.apply_range(planned.timing.clone()),
+                            velocity,
+                            Some(planned.phrase_end).filter(|_| planned.layer == DrumLayer::Fill),
+                            &mut humanize_rng,
+                        );
+
+                        DrumHit {
+                            hit: planned.hit,
+                            velocity,
                         }
+                        .over(timing)
                     })
-                    .collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+
+                // Mark the start of each section with an accent
+                let accent = Some(drum_part.timing.start)
+                    .filter(|start| *start == section.timing.start)
+                    .map(|start| {
+                        DrumHit {
+                            hit: voices.accent,
+                            velocity: DrumLayer::Accent
+                                .velocity(velocities.velocity(start, &mut rng) as i32),
+                        }
+                        .over(start..(start + ts.element.beat()))
+                    });
+
+                Ok(hits.into_iter().chain(accent).collect::<Vec<_>>())
+            })
+    }
+
+    /// Generates a free rhythm for each distinct phrase length, repeated for every phrase of that
+    /// length with a forced first hit.
+    fn random_hits(
+        drum_part: SegmentRef<Self>,
+        dividers: &[SegmentRef<PhraseDivider>],
+        ts: &TimeSignature,
+        voices: &DrumVoices,
+        context: &CompositionContext,
+    ) -> Vec<DrumNote> {
+        let mut rng = context.rng();
+        let mut phrase_lengths = dividers
+            .iter()
+            .map(|div| div.timing.len())
+            .collect::<Vec<_>>();
+        phrase_lengths.sort();
+        phrase_lengths.dedup();
+
+        let hit_probabilities = voices.weighted_hits();
+        let dist = WeightedIndex::new(hit_probabilities.iter().map(|i| i.1)).unwrap();
+
+        let rest_probability = rng.gen_range(0.3..=0.9);
+
+        let drum_beats = phrase_lengths
+            .iter()
+            .map(|l| {
+                let rhythm_precision = ts.quarter_beat();
+                let mut beat_rhythm = Rhythm::random(
+                    l - rhythm_precision,
+                    ts,
+                    |n| {
+                        (((n - rhythm_precision) as f32).clamp(0.0, ts.beat() as f32)
+                            / ts.beat() as f32)
+                            .powf(0.1)
+                    },
+                    |_| rest_probability,
+                    &mut rng,
+                );
+                beat_rhythm = Rhythm::from([rhythm_precision]) + beat_rhythm;
+
+                let hits = beat_rhythm
+                    .iter()
+                    .map(|_| hit_probabilities[dist.sample(&mut rng)].0)
+                    .collect::<Vec<_>>();
+
+                (*l, (beat_rhythm, hits))
+            })
+            .collect::<HashMap<_, _>>();
+
+        dividers
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, div)| {
+                if let Some((rhythm, hits)) = drum_beats.get(&div.timing.len()) {
+                    let mut alt_hit_rng = context.rng_with_seed(idx);
+                    let mut modified_rhythm = rhythm.clone();
+                    let mut modified_hits = hits.clone();
+                    let forced_hit = if (div.timing.start - drum_part.timing.start) % ts.bar() == 0
+                    {
+                        voices.kick
+                    } else {
+                        [voices.backbeat, voices.kick][alt_hit_rng.gen_range(0..=1)]
+                    };
+                    if hits.first().is_some() {
+                        modified_hits[0] = forced_hit;
+                        modified_rhythm.0[0].is_rest = false;
+                    };
+
+                    modified_rhythm
+                        .iter_over(div)
+                        .filter(|div| !div.is_rest)
+                        .zip(modified_hits.iter().cycle())
+                        .map(|(sub, drum_hit)| DrumNote {
+                            hit: *drum_hit,
+                            timing: sub.start..sub.end,
+                            phrase_end: div.timing.end,
+                            velocity_offset: 0,
+                            layer: DrumLayer::Groove,
+                        })
+                        .collect()
+                } else {
+                    vec![]
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redact_composer::elements::Part;
+    use redact_composer::musical::elements::{Mode, Scale};
+    use redact_composer::musical::{ChordShape, PitchClass};
+    use redact_composer::Composer;
+
+    fn c_major() -> Key {
+        Key::from((PitchClass(0), Scale::Major, Mode::Ionian))
+    }
+
+    fn chord(key: &Key, root: u8) -> Chord {
+        key.chords_with_shape(ChordShape::triad())
+            .into_iter()
+            .find(|chord| chord.root() == PitchClass(root))
+            .unwrap()
+    }
+
+    #[test]
+    fn harmonizes_diatonically() {
+        let key = c_major();
+
+        // E -> G a third above, C <- E a third below
+        assert_eq!(
+            Harmony::ThirdAbove.harmonize(Note(64), &key, &chord(&key, 0)),
+            Some(Note(67))
+        );
+        assert_eq!(
+            Harmony::ThirdBelow.harmonize(Note(64), &key, &chord(&key, 0)),
+            Some(Note(60))
+        );
+    }
+
+    #[test]
+    fn avoids_clashing_with_chord() {
+        let key = c_major();
+
+        // C would clash with the B of a G chord, and B is a second above A, so D is used
+        assert_eq!(
+            Harmony::ThirdAbove.harmonize(Note(69), &key, &chord(&key, 7)),
+            Some(Note(74))
+        );
+    }
+
+    /// A bar with a kick-locked bass part placed ahead of the drums it follows.
+    #[derive(Element, Serialize, Deserialize, Debug)]
+    struct KickLockedBar;
+
+    #[test]
+    fn kick_locked_bass_waits_for_drums() {
+        let engine = renderers()
+            + AdhocRenderer::<ChordMarkers>::new(|markers, _| {
+                Ok(vec![chord(&c_major(), 0).over(markers)])
             })
+            + AdhocRenderer::<KickLockedBar>::new(|bar, _| {
+                Ok(vec![
+                    Section.over(bar),
+                    TimeSignature {
+                        beats_per_bar: 4,
+                        beat_length: bar.timing.len() / 4,
+                    }
+                    .over(bar),
+                    c_major().over(bar),
+                    PhraseDivider.over(bar),
+                    ChordMarkers.over(bar),
+                    Part::instrument(BassPart::styled(
+                        Instrument::ElectricBassFinger,
+                        BassStyle::KickLocked,
+                    ))
+                    .over(bar),
+                    Part::percussion(DrumPart::new(DrumKit::from(0))).over(bar),
+                ])
+            });
+        let composer = Composer::from(engine);
+        let composition =
+            composer.compose(KickLockedBar.over(0..composer.options.ticks_per_beat * 4));
+
+        let segments = composition
+            .tree
+            .iter()
+            .map(|node| &node.value.segment)
+            .collect::<Vec<_>>();
+        let kicks = segments
+            .iter()
+            .filter(|segment| {
+                segment
+                    .element_as::<DrumHit>()
+                    .is_some_and(|hit| DrumRole::of(hit.hit) == Some(DrumRole::Kick))
+            })
+            .map(|segment| segment.timing.start)
+            .collect::<Vec<_>>();
+        let bass_starts = segments
+            .iter()
+            .filter(|segment| segment.element_as::<PlayNote>().is_some())
+            .map(|segment| segment.timing.start)
+            .collect::<Vec<_>>();
+
+        assert!(!kicks.is_empty());
+        assert!(kicks.iter().all(|kick| bass_starts.contains(kick)));
+    }
+
+    #[test]
+    fn skips_notes_beyond_scale() {
+        let key = c_major();
+        let lowest = key.root().in_octave(1);
+
+        assert_eq!(
+            Harmony::ThirdBelow.harmonize(lowest, &key, &chord(&key, 0)),
+            None
+        );
+        assert_eq!(
+            Harmony::SixthBelow.harmonize(lowest + Interval(2), &key, &chord(&key, 0)),
+            None
+        );
     }
 }
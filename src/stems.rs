@@ -0,0 +1,207 @@
+use crate::midi::PartTrack;
+use crate::mixing::PartRole;
+use midly::num::u28;
+use midly::{Header, MetaMessage, Smf, Track, TrackEvent, TrackEventKind};
+use redact_composer::midi::gm::elements::Instrument;
+use std::collections::BTreeMap;
+
+/// Converts `composition` into one file per part, named by its role and instrument.
+pub fn split(composition: &redact_composer::Composition) -> Vec<(String, Smf<'static>)> {
+    let tracks = crate::midi::tracks(composition);
+
+    stems(tracks.header, tracks.shared, tracks.parts)
+}
+
+fn stems(
+    header: Header,
+    shared: Vec<Track<'static>>,
+    parts: Vec<PartTrack>,
+) -> Vec<(String, Smf<'static>)> {
+    let end = parts
+        .iter()
+        .map(|part| track_len(&part.track))
+        .chain(shared.iter().map(track_len))
+        .max()
+        .unwrap_or(0);
+
+    let mut stems: BTreeMap<(Option<PartRole>, bool, Option<u8>), Vec<Track<'static>>> =
+        BTreeMap::new();
+    for part in parts {
+        stems
+            .entry((part.role, part.is_percussion(), part.program))
+            .or_default()
+            .push(part.track);
+    }
+
+    stems
+        .into_iter()
+        .map(|((role, percussion, program), tracks)| {
+            let mut stem = Smf::new(header);
+            stem.tracks = shared
+                .iter()
+                .chain(tracks.iter())
+                .map(|track| extend_to(track, end))
+                .collect();
+
+            (stem_name(role, percussion, program), stem)
+        })
+        .collect()
+}
+
+/// Names a stem by its role (lead, harmony, bass, drums or extra) and instrument or drum kit.
+fn stem_name(role: Option<PartRole>, percussion: bool, program: Option<u8>) -> String {
+    let role = role
+        .map(|role| role.name())
+        .unwrap_or_else(|| String::from("part"));
+    let instrument = match (percussion, program) {
+        (true, program) => format!("kit{}", program.unwrap_or(0)),
+        (false, Some(program)) => format!("{:?}", Instrument::from(program)),
+        (false, None) => String::from("unknown"),
+    };
+
+    format!("{}-{}", role, instrument)
+}
+
+fn track_len(track: &Track) -> u32 {
+    track.iter().map(|event| event.delta.as_int()).sum()
+}
+
+/// Copies `track`, moving its end to `end` if it would end earlier.
+fn extend_to<'a>(track: &Track<'a>, end: u32) -> Track<'a> {
+    let mut extended = track
+        .iter()
+        .filter(|event| !matches!(event.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack)))
+        .copied()
+        .collect::<Vec<_>>();
+    let remaining = end.saturating_sub(track_len(&extended));
+
+    extended.push(TrackEvent {
+        delta: u28::new(remaining),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    extended
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u15, u4, u7};
+    use midly::{Format, MidiMessage, Timing};
+
+    fn note(channel: u8, start: u32, len: u32) -> Track<'static> {
+        let key = u7::new(60);
+        let midi = |message| TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message,
+        };
+
+        vec![
+            TrackEvent {
+                delta: u28::new(start),
+                kind: midi(MidiMessage::NoteOn {
+                    key,
+                    vel: u7::new(100),
+                }),
+            },
+            TrackEvent {
+                delta: u28::new(len),
+                kind: midi(MidiMessage::NoteOff {
+                    key,
+                    vel: u7::new(0),
+                }),
+            },
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            },
+        ]
+    }
+
+    fn part(role: PartRole, program: u8, channel: u8, start: u32) -> PartTrack {
+        PartTrack {
+            role: Some(role),
+            program: Some(program),
+            channel,
+            track: note(channel, start, 100),
+        }
+    }
+
+    fn header() -> Header {
+        Header::new(Format::Parallel, Timing::Metrical(u15::new(480)))
+    }
+
+    #[test]
+    fn parts_with_same_role_and_instrument_share_a_stem() {
+        let stems = stems(
+            header(),
+            vec![],
+            vec![
+                part(PartRole::Lead, 0, 0, 0),
+                part(PartRole::Lead, 0, 1, 1000),
+            ],
+        );
+
+        assert_eq!(stems.len(), 1);
+        assert_eq!(stems[0].0, "lead-AcousticGrandPiano");
+        assert_eq!(stems[0].1.tracks.len(), 3);
+    }
+
+    #[test]
+    fn roles_sharing_an_instrument_get_separate_stems() {
+        let stems = stems(
+            header(),
+            vec![],
+            vec![
+                part(PartRole::Extra(0), 40, 0, 0),
+                part(PartRole::Harmony, 40, 1, 0),
+                part(PartRole::Extra(1), 40, 2, 0),
+                part(PartRole::Drums, 0, 9, 0),
+            ],
+        );
+        let names = stems
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            names,
+            vec![
+                "harmony-Violin",
+                "drums-kit0",
+                "extra1-Violin",
+                "extra2-Violin"
+            ]
+        );
+    }
+
+    #[test]
+    fn stems_keep_shared_tracks_and_line_up() {
+        let tempo = vec![
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::Tempo(midly::num::u24::new(500_000))),
+            },
+            TrackEvent {
+                delta: u28::new(0),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            },
+        ];
+        let stems = stems(
+            header(),
+            vec![tempo],
+            vec![
+                part(PartRole::Bass, 33, 0, 0),
+                part(PartRole::Lead, 0, 1, 1900),
+            ],
+        );
+
+        for (_, stem) in &stems {
+            assert!(matches!(
+                stem.tracks[0][0].kind,
+                TrackEventKind::Meta(MetaMessage::Tempo(_))
+            ));
+            assert!(stem.tracks.iter().all(|track| track_len(track) == 2000));
+        }
+    }
+}
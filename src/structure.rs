@@ -1,8 +1,15 @@
 use crate::chord_progression::{ChordMarkers, RandomChordProgression};
-use crate::parts::{BassPart, DrumPart, MelodyPart};
-use crate::util::generate_sawtooth_fn;
+use crate::drums::DrumDynamics;
+use crate::dynamics::{Dynamics, Hairpin};
+use crate::feel::Polyrhythm;
+use crate::melody::PhraseEndings;
+use crate::mixing::PartRole;
+use crate::orchestration::InstrumentationChange;
+use crate::parts::{BassPart, BassStyle, DrumPart, Harmony, HarmonyPart, MelodyPart};
+use crate::style::Style;
+use crate::util::Modulation;
 use crate::Instrumentation;
-use rand::prelude::IteratorRandom;
+use rand::prelude::{IteratorRandom, SliceRandom};
 use rand::Rng;
 use redact_composer::elements::Part;
 use redact_composer::musical::elements::TimeSignature;
@@ -38,7 +45,27 @@ impl Sections {
             let trimmed_len =
                 sections.timing.len() - sections.timing.len() % (min_section_length * 2);
             if trimmed_len <= min_section_length {
-                Ok(vec![Section.over(sections)])
+                // Sections after the first sometimes change the instrumentation, consistently
+                // across sections sharing a name
+                let change = if sections.timing.start > 0 {
+                    let instrumentation = Instrumentation::during(
+                        context,
+                        sections.timing.start..sections.timing.end,
+                    )?;
+                    let style = Style::during(context, sections.timing.start..sections.timing.end);
+
+                    InstrumentationChange::random(
+                        &instrumentation,
+                        &style,
+                        &mut context.rng_with_seed("instrumentation_change"),
+                    )
+                } else {
+                    None
+                };
+
+                Ok(once(Section.over(sections))
+                    .chain(change.map(|change| change.over(sections)))
+                    .collect::<Vec<_>>())
             } else {
                 let num_splits = (2..=6)
                     .filter(|divisor| trimmed_len % (divisor * min_section_length) == 0)
@@ -69,11 +96,8 @@ impl Section {
     fn renderer() -> impl Renderer<Element = Section> {
         AdhocRenderer::<Self>::new(|section, ctx| {
             let mut rng = ctx.rng();
-            let instrumentation = ctx
-                .find::<Instrumentation>()
-                .with_timing(During, section)
-                .require()?
-                .element;
+            let instrumentation =
+                Instrumentation::during(ctx, section.timing.start..section.timing.end)?;
             let ts = ctx
                 .find::<TimeSignature>()
                 .with_timing(During, section)
@@ -102,14 +126,39 @@ impl Section {
                 .flat_map(|div| div.try_into().ok())
                 .collect::<Vec<SegmentRef<PhraseDivider>>>();
 
+            let mut dynamics_rng = ctx.rng_with_seed("dynamics");
+            let dynamics = once(Dynamics::random(&mut dynamics_rng).over(section))
+                .chain(once(DrumDynamics::random(&mut dynamics_rng).over(section)))
+                .chain(
+                    Polyrhythm::random(ts, &mut ctx.rng_with_seed("polyrhythm"))
+                        .map(|polyrhythm| polyrhythm.over(section)),
+                )
+                .chain(
+                    typed_dividers
+                        .iter()
+                        .map(|div| Hairpin::random(&mut dynamics_rng).over(div)),
+                )
+                .chain(once(
+                    PhraseEndings::random(&mut ctx.rng_with_seed("phrase_endings")).over(section),
+                ))
+                .collect::<Vec<_>>();
+
+            let style = Style::during(ctx, section.timing.start..section.timing.end);
+            let bass_style =
+                BassStyle::random(&style.bass_styles, &mut ctx.rng_with_seed("bass_style"));
             let bass_parts = section
                 .timing
                 .divide_into(section.timing.len() / 4)
                 .into_iter()
                 .map(|divided_timing| {
-                    Part::instrument(BassPart::new(instrumentation.bass))
-                        .over(divided_timing)
-                        .named("Bass".to_string())
+                    match bass_style {
+                        BassStyle::Directed => {
+                            Part::instrument(BassPart::new(instrumentation.bass))
+                        }
+                        style => Part::instrument(BassPart::styled(instrumentation.bass, style)),
+                    }
+                    .over(divided_timing)
+                    .named("Bass".to_string())
                 });
 
             let drum_parts = section
@@ -124,7 +173,32 @@ impl Section {
 
             let period = section.timing.len() as f32 / 4.0;
             let offset = rng.gen_range(0.0..period);
-            let sawtooth = generate_sawtooth_fn(period, offset);
+            let activation_curve = Modulation::sawtooth(period, offset);
+
+            // Sometimes the first extra instrument follows the lead in harmony, leaving its own
+            // line while the lead plays.
+            let harmony = instrumentation
+                .extras
+                .first()
+                .filter(|_| rng.gen_bool(0.4))
+                .map(|inst| (*inst, *Harmony::values().choose(&mut rng).unwrap()));
+
+            let play_times = |activation: Range<f32>| {
+                typed_dividers
+                    .iter()
+                    .filter(|div| {
+                        let s_start = activation_curve.value(div.timing.start as f32);
+                        let s_end = activation_curve.value(div.timing.end as f32);
+                        activation.intersects(&(s_start..s_end))
+                            || s_start > s_end
+                                && (activation.intersects(&(s_start..1.0))
+                                    || activation.intersects(&(0.0..s_end)))
+                    })
+                    .map(|div| *div.timing)
+                    .collect::<Vec<_>>()
+                    .join()
+            };
+            let lead_times = play_times(0.0..0.7);
 
             let melody_parts3 = once(&instrumentation.melody)
                 .chain(instrumentation.extras.iter())
@@ -137,30 +211,37 @@ impl Section {
                     } else {
                         0.7..1.0
                     };
+                    let role = match idx {
+                        0 => PartRole::Lead,
+                        idx => PartRole::Extra(idx - 1),
+                    };
 
-                    let play_times = typed_dividers
-                        .iter()
-                        .filter(|div| {
-                            let s_start = sawtooth(div.timing.start as f32);
-                            let s_end = sawtooth(div.timing.end as f32);
-                            activation.intersects(&(s_start..s_end))
-                                || s_start > s_end
-                                    && (activation.intersects(&(s_start..1.0))
-                                        || activation.intersects(&(0.0..s_end)))
+                    play_times(activation)
+                        .into_iter()
+                        .filter(|timing| {
+                            idx != 1
+                                || harmony.is_none()
+                                || !lead_times.iter().any(|lead| lead.intersects(timing))
                         })
-                        .map(|div| *div.timing)
-                        .collect::<Vec<_>>()
-                        .join();
+                        .flat_map(|play_timing| {
+                            let name = ((idx as f32
+                                * activation_curve.value(play_timing.start as f32))
+                                as i32)
+                                .to_string();
 
-                    play_times
-                        .into_iter()
-                        .map(|play_timing| {
-                            Part::instrument(MelodyPart::new(*inst))
-                                .over(play_timing)
-                                .named(
-                                    ((idx as f32 * sawtooth(play_timing.start as f32)) as i32)
-                                        .to_string(),
-                                )
+                            match harmony {
+                                Some((harmony_inst, harmony)) if idx == 0 => vec![
+                                    Part::instrument(MelodyPart::harmonized(*inst, role))
+                                        .over(play_timing)
+                                        .named(name.clone()),
+                                    Part::instrument(HarmonyPart::new(harmony_inst, harmony))
+                                        .over(play_timing)
+                                        .named(name),
+                                ],
+                                _ => vec![Part::instrument(MelodyPart::new(*inst, role))
+                                    .over(play_timing)
+                                    .named(name)],
+                            }
                         })
                         .collect::<Vec<_>>()
                 })
@@ -172,6 +253,8 @@ impl Section {
             ]
             .into_iter()
             .chain(dividers)
+            .chain(dynamics)
+            .chain(once(activation_curve.clone().over(section)))
             .chain(bass_parts)
             .chain(drum_parts)
             .chain(melody_parts3)
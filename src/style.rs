@@ -0,0 +1,333 @@
+use crate::orchestration::RandomInstrumentation;
+use crate::parts::BassStyle;
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use redact_composer::midi::elements::DrumKit;
+use redact_composer::midi::gm::elements::Instrument;
+use redact_composer::midi::gm::Instruments;
+use redact_composer::render::context::CompositionContext;
+use redact_composer::render::context::TimingRelation::During;
+use redact_composer::Element;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+/// A relative weight for choosing a number of beats per bar.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct MeterWeight {
+    pub beats_per_bar: i32,
+    pub weight: i32,
+}
+
+/// Constraints keeping the randomly chosen aspects of a composition coherent with each other.
+#[derive(Element, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Style {
+    pub name: String,
+    pub min_bpm: u32,
+    pub max_bpm: u32,
+    pub meters: Vec<MeterWeight>,
+    /// Instruments for the melody and extra parts, which shouldn't overlap `bass_instruments`.
+    pub melody_instruments: Vec<Instrument>,
+    pub bass_instruments: Vec<Instrument>,
+    /// Drum kits, from
+    /// [`Instrumentation::drum_instruments`](crate::orchestration::Instrumentation::drum_instruments).
+    pub drum_kits: Vec<DrumKit>,
+    pub bass_styles: Vec<BassStyle>,
+    /// Names of the [`GrooveTemplate`](crate::drums::GrooveTemplate)s drum parts may use.
+    pub grooves: Vec<String>,
+    /// Probability of the composition being swung.
+    pub swing_probability: f64,
+    /// Pinned instruments and pools overriding the ones above, and the number of extras.
+    pub instrumentation: RandomInstrumentation,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self::free()
+    }
+}
+
+impl Style {
+    /// No constraints beyond the defaults of each random choice.
+    pub fn free() -> Style {
+        Style {
+            name: String::from("free"),
+            min_bpm: 90,
+            max_bpm: 160,
+            meters: vec![],
+            melody_instruments: vec![],
+            bass_instruments: vec![],
+            drum_kits: vec![],
+            bass_styles: vec![],
+            grooves: vec![],
+            swing_probability: 0.4,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn lo_fi() -> Style {
+        Style {
+            name: String::from("lo-fi"),
+            min_bpm: 70,
+            max_bpm: 92,
+            meters: Self::meters(&[(4, 6), (3, 1)]),
+            melody_instruments: vec![
+                Instrument::ElectricPiano1,
+                Instrument::ElectricPiano2,
+                Instrument::Vibraphone,
+                Instrument::MusicBox,
+                Instrument::AcousticGuitarNylon,
+                Instrument::ElectricGuitarJazz,
+                Instrument::Flute,
+            ],
+            bass_instruments: vec![
+                Instrument::AcousticBass,
+                Instrument::ElectricBassFinger,
+                Instrument::FretlessBass,
+            ],
+            drum_kits: Self::kits(&[0, 8, 25]),
+            bass_styles: vec![BassStyle::Directed, BassStyle::Walking],
+            grooves: Self::grooves(&["rock", "half-time", "shuffle"]),
+            swing_probability: 0.8,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn chiptune() -> Style {
+        Style {
+            name: String::from("chiptune"),
+            min_bpm: 130,
+            max_bpm: 170,
+            meters: Self::meters(&[(4, 5), (3, 1)]),
+            melody_instruments: vec![Instrument::LeadSquare, Instrument::LeadSawtooth],
+            bass_instruments: vec![Instrument::SynthBass1, Instrument::SynthBass2],
+            drum_kits: Self::kits(&[24, 25]),
+            bass_styles: vec![BassStyle::Riff, BassStyle::KickLocked],
+            grooves: Self::grooves(&["four-on-the-floor", "rock", "breakbeat"]),
+            swing_probability: 0.0,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn orchestral() -> Style {
+        Style {
+            name: String::from("orchestral"),
+            min_bpm: 60,
+            max_bpm: 110,
+            meters: Self::meters(&[(4, 3), (3, 3), (2, 1), (6, 1)]),
+            melody_instruments: (Instruments::strings() - Instrument::Timpani
+                + Instruments::brass()
+                - Instrument::SynthBrass1
+                - Instrument::SynthBrass2
+                + Instruments::reed()
+                + Instruments::pipe()
+                - Instrument::Cello
+                - Instrument::Contrabass
+                - Instrument::Bassoon
+                - Instrument::Tuba)
+                .into(),
+            bass_instruments: vec![
+                Instrument::Cello,
+                Instrument::Contrabass,
+                Instrument::Bassoon,
+                Instrument::Tuba,
+            ],
+            drum_kits: Self::kits(&[32, 40]),
+            bass_styles: vec![BassStyle::Directed],
+            grooves: Self::grooves(&["half-time"]),
+            swing_probability: 0.0,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn rock() -> Style {
+        Style {
+            name: String::from("rock"),
+            min_bpm: 100,
+            max_bpm: 150,
+            meters: Self::meters(&[(4, 8), (3, 1)]),
+            melody_instruments: vec![
+                Instrument::OverdrivenGuitar,
+                Instrument::DistortionGuitar,
+                Instrument::ElectricGuitarClean,
+                Instrument::RockOrgan,
+                Instrument::DrawbarOrgan,
+            ],
+            bass_instruments: vec![Instrument::ElectricBassPick, Instrument::ElectricBassFinger],
+            drum_kits: Self::kits(&[0, 8, 16]),
+            bass_styles: vec![BassStyle::KickLocked, BassStyle::Riff, BassStyle::Directed],
+            grooves: Self::grooves(&["rock", "half-time", "four-on-the-floor"]),
+            swing_probability: 0.1,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn ambient() -> Style {
+        Style {
+            name: String::from("ambient"),
+            min_bpm: 60,
+            max_bpm: 85,
+            meters: Self::meters(&[(4, 3), (3, 2), (5, 1), (7, 1)]),
+            melody_instruments: Vec::<Instrument>::from(
+                Instruments::synth_pad() - Instrument::PadNewAge - Instrument::PadPolysynth,
+            )
+            .into_iter()
+            .chain([
+                Instrument::Celesta,
+                Instrument::Vibraphone,
+                Instrument::OrchestralHarp,
+            ])
+            .collect(),
+            bass_instruments: vec![
+                Instrument::FretlessBass,
+                Instrument::PadNewAge,
+                Instrument::PadPolysynth,
+            ],
+            drum_kits: Self::kits(&[25, 40]),
+            bass_styles: vec![BassStyle::Directed],
+            grooves: Self::grooves(&["half-time"]),
+            swing_probability: 0.0,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn jazz_waltz() -> Style {
+        Style {
+            name: String::from("jazz-waltz"),
+            min_bpm: 120,
+            max_bpm: 180,
+            meters: Self::meters(&[(3, 1)]),
+            melody_instruments: vec![
+                Instrument::AcousticGrandPiano,
+                Instrument::Vibraphone,
+                Instrument::ElectricGuitarJazz,
+                Instrument::TenorSax,
+                Instrument::AltoSax,
+                Instrument::Clarinet,
+                Instrument::Trumpet,
+                Instrument::MutedTrumpet,
+            ],
+            bass_instruments: vec![Instrument::AcousticBass],
+            drum_kits: Self::kits(&[32, 40]),
+            bass_styles: vec![BassStyle::Walking],
+            grooves: vec![],
+            swing_probability: 0.9,
+            instrumentation: RandomInstrumentation::default(),
+        }
+    }
+
+    pub fn presets() -> Vec<Style> {
+        vec![
+            Self::lo_fi(),
+            Self::chiptune(),
+            Self::orchestral(),
+            Self::rock(),
+            Self::ambient(),
+            Self::jazz_waltz(),
+        ]
+    }
+
+    pub fn preset(name: &str) -> Option<Style> {
+        Self::presets().into_iter().find(|style| style.name == name)
+    }
+
+    /// Chooses one of the presets, or sometimes the unconstrained [`Style::free`].
+    pub fn random(rng: &mut impl Rng) -> Style {
+        let mut styles = Self::presets();
+        styles.push(Self::free());
+
+        styles.choose(rng).cloned().unwrap_or_else(Self::free)
+    }
+
+    /// Loads a custom style from a JSON file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Style, Box<dyn Error>> {
+        let json = fs::read_to_string(path)?;
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Looks up the [`Style`] during `timing`, defaulting to [`Style::free`].
+    pub fn during(ctx: &CompositionContext, timing: Range<i32>) -> Style {
+        ctx.find::<Style>()
+            .with_timing(During, timing)
+            .get()
+            .map(|style| style.element.clone())
+            .unwrap_or_else(Self::free)
+    }
+
+    /// Chooses from `options` if non-empty, otherwise from `defaults`.
+    pub fn choose_from<T: Copy>(options: &[T], defaults: &[T], rng: &mut impl Rng) -> Option<T> {
+        if options.is_empty() {
+            defaults.choose(rng).copied()
+        } else {
+            options.choose(rng).copied()
+        }
+    }
+
+    fn meters(weights: &[(i32, i32)]) -> Vec<MeterWeight> {
+        weights
+            .iter()
+            .map(|(beats_per_bar, weight)| MeterWeight {
+                beats_per_bar: *beats_per_bar,
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    fn kits(programs: &[u8]) -> Vec<DrumKit> {
+        programs
+            .iter()
+            .map(|program| DrumKit::from(*program))
+            .collect()
+    }
+
+    fn grooves(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestration::Instrumentation;
+
+    #[test]
+    fn preset_pools_are_disjoint() {
+        for style in Style::presets() {
+            assert!(
+                style
+                    .melody_instruments
+                    .iter()
+                    .all(|instrument| !style.bass_instruments.contains(instrument)),
+                "{} shares instruments between melody and bass",
+                style.name
+            );
+        }
+    }
+
+    #[test]
+    fn missing_fields_default_to_free() {
+        let style: Style = serde_json::from_str(r#"{ "name": "mine", "max_bpm": 120 }"#).unwrap();
+
+        assert_eq!(style.name, "mine");
+        assert_eq!(style.min_bpm, Style::free().min_bpm);
+        assert_eq!(style.max_bpm, 120);
+        assert_eq!(style.swing_probability, Style::free().swing_probability);
+    }
+
+    #[test]
+    fn preset_kits_are_curated() {
+        let kits = Instrumentation::drum_instruments();
+
+        for style in Style::presets() {
+            assert!(
+                style.drum_kits.iter().all(|kit| kits.contains(kit)),
+                "{} uses an uncurated drum kit",
+                style.name
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::style::Style;
 use rand::prelude::{IteratorRandom, SliceRandom};
 use rand::Rng;
 use redact_composer::musical::elements::{Key, Mode, Scale, TimeSignature};
@@ -7,6 +8,9 @@ use redact_composer::timing::elements::Tempo;
 use redact_composer::util::IntoSegment;
 use redact_composer::{Element, Renderer};
 use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+use std::ops::{Add, Mul};
+use twox_hash::XxHash64;
 
 pub fn renderers() -> RenderEngine {
     RenderEngine::new()
@@ -40,8 +44,13 @@ impl RandomTimeSignature {
     pub fn renderer() -> impl Renderer<Element = Self> {
         AdhocRenderer::<Self>::new(|segment, context| {
             let mut rng = context.rng();
+            let style = Style::during(context, segment.timing.start..segment.timing.end);
 
-            let beats_per_bar = (2..=7).chain([9, 11, 13]).choose(&mut rng).unwrap();
+            let beats_per_bar = style
+                .meters
+                .choose_weighted(&mut rng, |meter| meter.weight)
+                .map(|meter| meter.beats_per_bar)
+                .unwrap_or_else(|_| (2..=7).chain([9, 11, 13]).choose(&mut rng).unwrap());
             let beat_length = context.beat_length();
 
             Ok(vec![TimeSignature {
@@ -60,8 +69,10 @@ impl RandomTempo {
     pub fn renderer() -> impl Renderer<Element = Self> {
         AdhocRenderer::<Self>::new(|segment, ctx| {
             let mut rng = ctx.rng();
+            let style = Style::during(ctx, segment.timing.start..segment.timing.end);
+            let bpm = rng.gen_range(style.min_bpm..=style.max_bpm.max(style.min_bpm));
 
-            Ok(vec![Tempo::from_bpm(rng.gen_range(90..=160)).over(segment)])
+            Ok(vec![Tempo::from_bpm(bpm).over(segment)])
         })
     }
 }
@@ -71,17 +82,387 @@ pub fn generate_sawtooth_fn(period: f32, offset: f32) -> impl Fn(f32) -> f32 {
     move |t: f32| (t + offset) / period - (0.5 + (t + offset) / period).floor() + 0.5
 }
 
-/// Merge two sawtooth functions, with relative amplitudes according to a given ratio (s1/s2).
-pub fn merge_sawtooth_fns(
-    s1: impl Fn(f32) -> f32,
-    s2: impl Fn(f32) -> f32,
-    ratio: f32,
-) -> impl Fn(f32) -> f32 {
-    let (first_scale, second_scale) = if ratio.abs() <= 1.0 {
-        (ratio, 1.0 - ratio)
-    } else {
-        (ratio / (ratio.abs() + 1.0), 1.0 / (ratio.abs() + 1.0))
-    };
-
-    move |t: f32| s1(t) * first_scale + s2(t) * second_scale
+/// A serializable curve over time, composed from oscillators, envelopes and noise.
+#[derive(Element, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Modulation {
+    Constant(f32),
+    Sine {
+        period: f32,
+        offset: f32,
+    },
+    /// Rises from 0.0 to 1.0 and back over each period.
+    Triangle {
+        period: f32,
+        offset: f32,
+    },
+    /// 1.0 for the first `duty` portion of each period, 0.0 for the rest.
+    Square {
+        period: f32,
+        offset: f32,
+        duty: f32,
+    },
+    /// Matches [`generate_sawtooth_fn`].
+    Sawtooth {
+        period: f32,
+        offset: f32,
+    },
+    /// Starts at 0.5, moving up or down by up to `max_step` every `step`, within `0.0..=1.0`.
+    RandomWalk {
+        seed: u64,
+        step: f32,
+        max_step: f32,
+    },
+    /// Seeded random values in `0.0..1.0` every `step`, linearly interpolated in between.
+    Noise {
+        seed: u64,
+        step: f32,
+    },
+    /// An envelope for a note held from 0.0 until `hold`: rising to 1.0 over `attack`, falling to
+    /// `sustain` over `decay`, then to 0.0 over `release` once released.
+    Adsr {
+        attack: f32,
+        decay: f32,
+        sustain: f32,
+        release: f32,
+        hold: f32,
+    },
+    /// Linear interpolation between `(time, value)` points in time order, holding the first and
+    /// last values beyond them.
+    Breakpoints(Vec<(f32, f32)>),
+    Add(Box<Modulation>, Box<Modulation>),
+    Multiply(Box<Modulation>, Box<Modulation>),
+    Clamp {
+        input: Box<Modulation>,
+        min: f32,
+        max: f32,
+    },
+    /// Maps `0.0..=1.0` of `input` linearly onto `min..=max`.
+    Remap {
+        input: Box<Modulation>,
+        min: f32,
+        max: f32,
+    },
+    /// Splits `0.0..=1.0` into `steps` equal bands, mapping `input` in each band to one of `steps`
+    /// evenly spaced levels from 0.0 to 1.0.
+    Quantize {
+        input: Box<Modulation>,
+        steps: u32,
+    },
+}
+
+impl Modulation {
+    pub fn sawtooth(period: f32, offset: f32) -> Modulation {
+        Modulation::Sawtooth { period, offset }
+    }
+
+    pub fn triangle(period: f32, offset: f32) -> Modulation {
+        Modulation::Triangle { period, offset }
+    }
+
+    /// Merges two modulations, with relative amplitudes according to a given ratio (self/other).
+    pub fn merge(self, other: Modulation, ratio: f32) -> Modulation {
+        let (first_scale, second_scale) = if ratio.abs() <= 1.0 {
+            (ratio, 1.0 - ratio)
+        } else {
+            (ratio / (ratio.abs() + 1.0), 1.0 / (ratio.abs() + 1.0))
+        };
+
+        self * Modulation::Constant(first_scale) + other * Modulation::Constant(second_scale)
+    }
+
+    pub fn value(&self, t: f32) -> f32 {
+        match self {
+            Modulation::Constant(value) => *value,
+            Modulation::Sine { period, offset } => {
+                0.5 + 0.5 * (std::f32::consts::TAU * (t + offset) / period).sin()
+            }
+            Modulation::Triangle { period, offset } => {
+                1.0 - (2.0 * generate_sawtooth_fn(*period, *offset)(t) - 1.0).abs()
+            }
+            Modulation::Square {
+                period,
+                offset,
+                duty,
+            } => {
+                if ((t + offset) / period).rem_euclid(1.0) < *duty {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Modulation::Sawtooth { period, offset } => generate_sawtooth_fn(*period, *offset)(t),
+            Modulation::RandomWalk {
+                seed,
+                step,
+                max_step,
+            } => {
+                let position = t.max(0.0) / step.max(f32::EPSILON);
+                let walk = (0..position as i64).fold(0.5_f32, |value, idx| {
+                    (value + (2.0 * seeded_unit(*seed, idx) - 1.0) * max_step).clamp(0.0, 1.0)
+                });
+                let next = (walk + (2.0 * seeded_unit(*seed, position as i64) - 1.0) * max_step)
+                    .clamp(0.0, 1.0);
+
+                walk + (next - walk) * position.fract()
+            }
+            Modulation::Noise { seed, step } => {
+                let position = t / step.max(f32::EPSILON);
+                let idx = position.floor() as i64;
+                let (current, next) = (seeded_unit(*seed, idx), seeded_unit(*seed, idx + 1));
+
+                current + (next - current) * (position - position.floor())
+            }
+            Modulation::Adsr {
+                attack,
+                decay,
+                sustain,
+                release,
+                hold,
+            } => {
+                let held = |t: f32| {
+                    if t < 0.0 {
+                        0.0
+                    } else if t < *attack {
+                        t / attack
+                    } else if t < attack + decay {
+                        1.0 - (1.0 - sustain) * (t - attack) / decay
+                    } else {
+                        *sustain
+                    }
+                };
+
+                if t < *hold {
+                    held(t)
+                } else {
+                    held(*hold) * (1.0 - (t - hold) / release.max(f32::EPSILON)).max(0.0)
+                }
+            }
+            Modulation::Breakpoints(points) => {
+                let after = points.iter().position(|(time, _)| *time > t);
+                match after {
+                    Some(0) => points[0].1,
+                    Some(idx) => {
+                        let ((t0, v0), (t1, v1)) = (points[idx - 1], points[idx]);
+
+                        v0 + (v1 - v0) * (t - t0) / (t1 - t0)
+                    }
+                    None => points.last().map(|(_, value)| *value).unwrap_or(0.0),
+                }
+            }
+            Modulation::Add(a, b) => a.value(t) + b.value(t),
+            Modulation::Multiply(a, b) => a.value(t) * b.value(t),
+            Modulation::Clamp { input, min, max } => input.value(t).clamp(*min, *max),
+            Modulation::Remap { input, min, max } => min + (max - min) * input.value(t),
+            Modulation::Quantize { input, steps } => {
+                let steps = (*steps).max(1);
+                let band = (input.value(t).clamp(0.0, 1.0) * steps as f32) as u32;
+
+                band.min(steps - 1) as f32 / (steps - 1).max(1) as f32
+            }
+        }
+    }
+}
+
+impl Add for Modulation {
+    type Output = Modulation;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Modulation::Add(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl Mul for Modulation {
+    type Output = Modulation;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Modulation::Multiply(Box::new(self), Box::new(rhs))
+    }
+}
+
+/// A deterministic value in `0.0..1.0` for each `seed` and `idx`.
+fn seeded_unit(seed: u64, idx: i64) -> f32 {
+    let mut hasher = XxHash64::with_seed(seed);
+    hasher.write_i64(idx);
+
+    (hasher.finish() >> 40) as f32 / (1_u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_values(modulation: &Modulation, expected: &[(f32, f32)]) {
+        for (t, value) in expected {
+            let actual = modulation.value(*t);
+            assert!(
+                (actual - value).abs() < 1e-4,
+                "{:?} at {}: {} != {}",
+                modulation,
+                t,
+                actual,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn oscillators_repeat_every_period() {
+        assert_values(
+            &Modulation::sawtooth(4.0, 2.0),
+            &[
+                (0.0, 0.0),
+                (1.0, 0.25),
+                (3.0, 0.75),
+                (4.0, 0.0),
+                (5.0, 0.25),
+            ],
+        );
+        assert_values(
+            &Modulation::triangle(4.0, 2.0),
+            &[(0.0, 0.0), (1.0, 0.5), (2.0, 1.0), (3.0, 0.5), (4.0, 0.0)],
+        );
+        assert_values(
+            &Modulation::Sine {
+                period: 4.0,
+                offset: 0.0,
+            },
+            &[(0.0, 0.5), (1.0, 1.0), (2.0, 0.5), (3.0, 0.0)],
+        );
+        assert_values(
+            &Modulation::Square {
+                period: 4.0,
+                offset: 0.0,
+                duty: 0.25,
+            },
+            &[
+                (0.0, 1.0),
+                (0.9, 1.0),
+                (1.0, 0.0),
+                (3.9, 0.0),
+                (4.0, 1.0),
+                (-3.5, 1.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn adsr_follows_envelope_stages() {
+        let adsr = Modulation::Adsr {
+            attack: 1.0,
+            decay: 1.0,
+            sustain: 0.5,
+            release: 2.0,
+            hold: 4.0,
+        };
+
+        assert_values(
+            &adsr,
+            &[
+                (-1.0, 0.0),
+                (0.5, 0.5),
+                (1.0, 1.0),
+                (1.5, 0.75),
+                (3.0, 0.5),
+                (5.0, 0.25),
+                (6.0, 0.0),
+                (10.0, 0.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn breakpoints_interpolate_and_hold_ends() {
+        let points = Modulation::Breakpoints(vec![(1.0, 0.2), (3.0, 0.6), (4.0, 0.0)]);
+
+        assert_values(
+            &points,
+            &[(0.0, 0.2), (1.0, 0.2), (2.0, 0.4), (3.5, 0.3), (9.0, 0.0)],
+        );
+        assert_values(&Modulation::Breakpoints(vec![]), &[(1.0, 0.0)]);
+    }
+
+    #[test]
+    fn combinators_apply_to_input() {
+        let ramp = || Modulation::Breakpoints(vec![(0.0, 0.0), (1.0, 1.0)]);
+
+        assert_values(
+            &(ramp() + Modulation::Constant(0.5)),
+            &[(0.0, 0.5), (1.0, 1.5)],
+        );
+        assert_values(
+            &(ramp() * Modulation::Constant(0.5)),
+            &[(0.5, 0.25), (1.0, 0.5)],
+        );
+        assert_values(
+            &ramp().merge(Modulation::Constant(1.0), 0.5),
+            &[(0.0, 0.5), (1.0, 1.0)],
+        );
+        assert_values(
+            &Modulation::Clamp {
+                input: Box::new(ramp()),
+                min: 0.25,
+                max: 0.75,
+            },
+            &[(0.0, 0.25), (0.5, 0.5), (1.0, 0.75)],
+        );
+        assert_values(
+            &Modulation::Remap {
+                input: Box::new(ramp()),
+                min: 40.0,
+                max: 110.0,
+            },
+            &[(0.0, 40.0), (0.5, 75.0), (1.0, 110.0)],
+        );
+    }
+
+    #[test]
+    fn quantize_yields_steps_levels() {
+        let ramp = Modulation::Breakpoints(vec![(0.0, 0.0), (1.0, 1.0)]);
+        let quantized = Modulation::Quantize {
+            input: Box::new(ramp.clone()),
+            steps: 3,
+        };
+
+        assert_values(
+            &quantized,
+            &[
+                (0.0, 0.0),
+                (0.3, 0.0),
+                (0.4, 0.5),
+                (0.6, 0.5),
+                (0.7, 1.0),
+                (1.0, 1.0),
+            ],
+        );
+
+        let single = Modulation::Quantize {
+            input: Box::new(ramp),
+            steps: 1,
+        };
+        assert_values(&single, &[(0.0, 0.0), (1.0, 0.0)]);
+    }
+
+    #[test]
+    fn random_modulations_are_seeded_and_bounded() {
+        let walk = Modulation::RandomWalk {
+            seed: 7,
+            step: 1.0,
+            max_step: 0.2,
+        };
+        let noise = Modulation::Noise { seed: 7, step: 1.0 };
+
+        assert_values(&walk, &[(0.0, 0.5)]);
+        for t in (0..40).map(|t| t as f32 * 0.25) {
+            for modulation in [&walk, &noise] {
+                let value = modulation.value(t);
+
+                assert!((0.0..=1.0).contains(&value));
+                assert_eq!(value, modulation.value(t));
+            }
+        }
+        assert_ne!(
+            noise.value(0.0),
+            Modulation::Noise { seed: 8, step: 1.0 }.value(0.0)
+        );
+    }
 }